@@ -1,57 +1,430 @@
-use crate::metrics::TimeFrame;
+use crate::budget::ThreadBudget;
+use crate::metrics::{
+    GaugeOperation, Histogram, Metric, Percentile, Registry, Statistics, TimeFrame, TimerResolution,
+};
 use chrono::{DateTime, Utc};
 use postgres::types::ToSql;
 use std::fmt::{Debug, Formatter};
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{mpsc, Arc, Mutex, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
 
-pub trait Backend {
-    fn publish(&mut self, time: &DateTime<Utc>, time_frame: &TimeFrame);
+pub trait Backend: Send {
+    fn publish(&mut self, time: &DateTime<Utc>, time_frame: &TimeFrame) -> Result<(), String>;
+
+    /// Unhealthy backend is built again before the next cycle.
+    fn is_healthy(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Published,
+    Failed(String),
+    TimedOut,
+    /// Backend was still publishing a previous time frame, so this one was skipped.
+    Busy,
+    /// Thread budget was exhausted, so this time frame was skipped.
+    Shed,
+}
+
+/// Backend shared between cycles, locked by the thread publishing through it.
+pub type Shared = Arc<Mutex<Box<dyn Backend>>>;
+
+/// Backend name, backend and its publish timeout.
+pub type Publisher = (String, Shared, Option<Duration>);
+
+/// Publishes to all backends at once, each on its own thread, and waits for every one of them.
+/// Once a backend exceeds its timeout, measured from when publishing started, it's reported as
+/// timed out. Thread of the timed out backend is left running in the background and holds the
+/// backend until publish returns, so the backend is reported as busy if it's still publishing in
+/// the next cycle.
+///
+/// Every backend receives the same `time`, which is expected to be the cycle boundary. When a
+/// backend starts publishing more than `skew_threshold` after it, a warning is logged.
+///
+/// Publishing threads are spawned within `budget`, and backend is skipped when there is no room
+/// for its thread.
+pub fn publish_all(
+    backends: &[Publisher],
+    time: &DateTime<Utc>,
+    time_frame: Arc<TimeFrame>,
+    skew_threshold: Option<Duration>,
+    budget: &ThreadBudget,
+) -> Vec<(String, Outcome)> {
+    let started = Instant::now();
+
+    let receivers = backends
+        .iter()
+        .map(|(name, backend, timeout)| {
+            log::trace!("Notifying backend {:?}", name);
+
+            if let (Some(threshold), Ok(skew)) = (
+                skew_threshold,
+                Utc::now().signed_duration_since(*time).to_std(),
+            ) {
+                if skew > threshold {
+                    log::warn!(
+                        "Backend {name} is publishing {skew:?} after the cycle ended, which exceeds {threshold:?}"
+                    );
+                }
+            }
+
+            let (sender, receiver) = mpsc::channel();
+            let time = *time;
+            let time_frame = time_frame.clone();
+            let backend = backend.clone();
+
+            let spawned = budget.try_spawn(move || {
+                let result = match backend.try_lock() {
+                    Ok(mut backend) => Some(backend.publish(&time, &time_frame)),
+                    Err(TryLockError::WouldBlock) => None,
+                    Err(TryLockError::Poisoned(_)) => {
+                        Some(Err("Backend panicked while publishing".to_string()))
+                    }
+                };
+
+                let _ = sender.send(result);
+            });
+
+            if spawned.is_none() {
+                log::warn!("Thread budget is exhausted, skipping backend {name}");
+
+                return (name, None);
+            }
+
+            (name, Some((receiver, *timeout)))
+        })
+        .collect::<Vec<_>>();
+
+    receivers
+        .into_iter()
+        .map(|(name, receiver)| {
+            let name = name.clone();
+
+            let Some((receiver, timeout)) = receiver else {
+                return (name, Outcome::Shed);
+            };
+
+            let outcome = match timeout {
+                None => receiver
+                    .recv()
+                    .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                Some(timeout) => receiver.recv_timeout(timeout.saturating_sub(started.elapsed())),
+            };
+
+            match outcome {
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    log::error!(
+                        "Backend {name} did not publish within {:?}, marking it as timed out",
+                        timeout.expect("Only backends with timeout can time out")
+                    );
+
+                    (name, Outcome::TimedOut)
+                }
+                Ok(None) => {
+                    log::warn!("Backend {name} is still publishing previous time frame, skipping");
+
+                    (name, Outcome::Busy)
+                }
+                Ok(Some(Err(err))) => {
+                    log::error!("Backend {name} failed to publish: {err}");
+
+                    (name, Outcome::Failed(err))
+                }
+                Ok(Some(Ok(()))) => (name, Outcome::Published),
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    log::error!("Backend {name} stopped publishing without an outcome");
+
+                    (
+                        name,
+                        Outcome::Failed("Backend panicked while publishing".to_string()),
+                    )
+                }
+            }
+        })
+        .collect()
+}
+
+pub type Constructed = Result<Box<dyn Backend>, String>;
+
+/// Publishes synthetic `<prefix>.selftest` gauge through every backend, to check that the whole
+/// write path works. Fails if a required backend could not be constructed or failed to publish.
+pub fn self_test(backends: Vec<(String, Constructed, bool)>, prefix: &str) -> Result<(), String> {
+    let mut registry = Registry::default();
+    registry.add(&Metric {
+        name: format!("{prefix}.selftest"),
+        kind: crate::metrics::MetricKind::Gauge(GaugeOperation::Set(1.)),
+    });
+
+    let time_frame = registry.finalize();
+    let time = Utc::now();
+
+    for (name, backend, required) in backends {
+        match backend.and_then(|mut backend| backend.publish(&time, &time_frame)) {
+            Ok(()) => log::info!("Self-test of backend {name} passed"),
+            Err(err) if required => {
+                return Err(format!(
+                    "Self-test of required backend {name} failed: {err}"
+                ))
+            }
+            Err(err) => log::warn!("Self-test of backend {name} failed: {err}"),
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub min_samples_for_percentiles: u64,
+    /// Percentiles published for every statistics.
+    pub percentiles: Vec<Percentile>,
+    /// Leaves out standard deviation, so it's never computed.
+    pub skip_std: bool,
+    /// Time counters are collected over, which counter sums are divided by to publish per-second
+    /// rate. Rate is left out when not set.
+    pub window: Option<Duration>,
+    /// Unit timing statistics are published in. Timings are kept in nanoseconds until then.
+    pub timing_resolution: TimerResolution,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            min_samples_for_percentiles: 0,
+            percentiles: [0.75, 0.9]
+                .map(|p| Percentile::try_from(p).expect("Default percentiles are within range"))
+                .to_vec(),
+            skip_std: false,
+            window: None,
+            timing_resolution: TimerResolution::NanoSeconds,
+        }
+    }
+}
+
+fn statistics_fields(stats: &Statistics, options: &Options) -> Vec<(String, f64)> {
+    let mut fields = vec![
+        ("count".to_string(), Some(stats.count() as f64)),
+        ("sum".to_string(), Some(stats.sum())),
+        ("avg".to_string(), stats.average()),
+    ];
+
+    if !options.skip_std {
+        fields.push(("std".to_string(), stats.std()));
+    }
+
+    fields.push(("min".to_string(), stats.min()));
+    fields.push(("max".to_string(), stats.max()));
+
+    if stats.count() >= options.min_samples_for_percentiles {
+        fields.push(("median".to_string(), stats.median()));
+
+        options
+            .percentiles
+            .iter()
+            .for_each(|p| fields.push((p.label(), stats.percentile(*p))));
+    }
+
+    // Known metrics without samples in the cycle only have count and sum
+    fields
+        .into_iter()
+        .filter_map(|(field, value)| Some((field, value?)))
+        .collect()
+}
+
+/// Statistics fields of a counter, along with its per-second rate over the window.
+fn counter_fields(stats: &Statistics, options: &Options) -> Vec<(String, f64)> {
+    let mut fields = statistics_fields(stats, options);
+
+    if let Some(window) = options.window.filter(|window| !window.is_zero()) {
+        fields.push(("rate".to_string(), stats.sum() / window.as_secs_f64()));
+    }
+
+    fields
+}
+
+/// Statistics fields of a timing, where everything but count is converted from nanoseconds to the
+/// timing resolution.
+fn timing_fields(stats: &Statistics, options: &Options) -> Vec<(String, f64)> {
+    let nanoseconds = options.timing_resolution.nanoseconds();
+
+    statistics_fields(stats, options)
+        .into_iter()
+        .map(|(field, value)| match field.as_str() {
+            "count" => (field, value),
+            _ => (field, value / nanoseconds),
+        })
+        .collect()
+}
+
+/// Count, sum and cumulative count of every bucket, named like `bucket.le_0_5` and `bucket.le_inf`,
+/// with the dot in the bound replaced so it does not read as a name separator.
+fn histogram_fields(histogram: &Histogram) -> Vec<(String, f64)> {
+    let mut fields = vec![
+        ("count".to_string(), histogram.count() as f64),
+        ("sum".to_string(), histogram.sum()),
+    ];
+
+    histogram.buckets().into_iter().for_each(|(bound, count)| {
+        let bound = bound.to_string().replace('.', "_");
+
+        fields.push((format!("bucket.le_{bound}"), count as f64))
+    });
+
+    fields
+}
+
+/// Published value referenced by name, either a gauge, a set, a statistics field like `abc.p90`
+/// or a histogram field like `abc.bucket.le_0_5`.
+pub fn value(time_frame: &TimeFrame, options: &Options, reference: &str) -> Option<f64> {
+    if let Some(value) = time_frame.gauges.get(reference) {
+        return Some(*value);
+    }
+
+    if let Some(value) = time_frame.sets.get(reference) {
+        return Some(*value as f64);
+    }
+
+    // Histogram fields can contain dots, so the name can not be split off at the last one
+    if let Some(value) = time_frame.histograms.iter().find_map(|(name, histogram)| {
+        let field = reference.strip_prefix(name.as_str())?.strip_prefix('.')?;
+
+        histogram_fields(histogram)
+            .into_iter()
+            .find_map(|(candidate, value)| (candidate == field).then_some(value))
+    }) {
+        return Some(value);
+    }
+
+    let (name, field) = reference.rsplit_once('.')?;
+
+    let fields = if let Some(stats) = time_frame.counters.get(name) {
+        counter_fields(stats, options)
+    } else if let Some(stats) = time_frame.timings.get(name) {
+        timing_fields(stats, options)
+    } else {
+        statistics_fields(time_frame.summaries.get(name)?, options)
+    };
+
+    fields
+        .into_iter()
+        .find_map(|(candidate, value)| (candidate == field).then_some(value))
+}
+
+#[derive(Debug, Clone, Default)]
+pub enum TimestampFormat {
+    #[default]
+    Rfc3339,
+    UnixSeconds,
+    UnixMillis,
+}
+
+impl TimestampFormat {
+    fn format(&self, time: &DateTime<Utc>) -> String {
+        match self {
+            TimestampFormat::Rfc3339 => time.to_rfc3339(),
+            TimestampFormat::UnixSeconds => time.timestamp().to_string(),
+            TimestampFormat::UnixMillis => time.timestamp_millis().to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConsoleOptions {
+    pub timestamp_format: TimestampFormat,
 }
 
 #[derive(Debug, Default)]
-pub struct Console {}
+pub struct Console {
+    options: Options,
+    console_options: ConsoleOptions,
+}
 
-impl Backend for Console {
-    fn publish(&mut self, time: &DateTime<Utc>, time_frame: &TimeFrame) {
-        println!("{}", time.to_rfc3339());
+impl Console {
+    pub fn new(options: Options, console_options: ConsoleOptions) -> Self {
+        Self {
+            options,
+            console_options,
+        }
+    }
+
+    fn render(&self, time: &DateTime<Utc>, time_frame: &TimeFrame) -> String {
+        let mut lines = vec![self.console_options.timestamp_format.format(time)];
 
         if !time_frame.gauges.is_empty() {
-            println!("Gauges:");
+            lines.push("Gauges:".to_string());
 
             time_frame
                 .gauges
                 .iter()
-                .for_each(|(name, value)| println!("  {name} - {value}"));
+                .for_each(|(name, value)| lines.push(format!("  {name} - {value}")));
+        }
+
+        if !time_frame.sets.is_empty() {
+            lines.push("Sets:".to_string());
+
+            time_frame
+                .sets
+                .iter()
+                .for_each(|(name, value)| lines.push(format!("  {name} - {value}")));
         }
 
-        if !time_frame.counters.is_empty() {
-            println!("Counters:");
+        type Fields = fn(&Statistics, &Options) -> Vec<(String, f64)>;
+
+        for (title, statistics, fields) in [
+            ("Counters:", &time_frame.counters, counter_fields as Fields),
+            ("Timings:", &time_frame.timings, timing_fields),
+            ("Summaries:", &time_frame.summaries, statistics_fields),
+        ] {
+            if statistics.is_empty() {
+                continue;
+            }
 
-            time_frame.counters.iter().for_each(|(name, stats)| {
-                println!("  {name}");
-                println!("    count: {}", stats.count());
-                println!("    sum: {}", stats.sum());
-                println!("    avg: {}", stats.average());
-                println!("    std: {}", stats.std());
-                println!("    median: {}", stats.median());
-                println!("    p75: {}", stats.percentile(0.75));
-                println!("    p90: {}", stats.percentile(0.90));
+            lines.push(title.to_string());
+
+            statistics.iter().for_each(|(name, stats)| {
+                lines.push(format!("  {name}"));
+
+                fields(stats, &self.options)
+                    .into_iter()
+                    .for_each(|(field, value)| lines.push(format!("    {field}: {value}")));
             });
         }
 
-        if !time_frame.timings.is_empty() {
-            println!("Timings:");
+        if !time_frame.histograms.is_empty() {
+            lines.push("Histograms:".to_string());
 
-            time_frame.timings.iter().for_each(|(name, stats)| {
-                println!("  {name}");
-                println!("    count: {}", stats.count());
-                println!("    sum: {}", stats.sum());
-                println!("    avg: {}", stats.average());
-                println!("    std: {}", stats.std());
-                println!("    median: {}", stats.median());
-                println!("    p75: {}", stats.percentile(0.75));
-                println!("    p90: {}", stats.percentile(0.90));
+            time_frame.histograms.iter().for_each(|(name, histogram)| {
+                lines.push(format!("  {name}"));
+
+                histogram_fields(histogram)
+                    .into_iter()
+                    .for_each(|(field, value)| lines.push(format!("    {field}: {value}")));
             });
         }
+
+        if !time_frame.derived.is_empty() {
+            lines.push("Derived:".to_string());
+
+            time_frame
+                .derived
+                .iter()
+                .for_each(|(name, value)| lines.push(format!("  {name} - {value}")));
+        }
+
+        lines.join("\n")
+    }
+}
+
+impl Backend for Console {
+    fn publish(&mut self, time: &DateTime<Utc>, time_frame: &TimeFrame) -> Result<(), String> {
+        println!("{}", self.render(time, time_frame));
+
+        Ok(())
     }
 }
 
@@ -66,10 +439,180 @@ create table metrics
     value float8,
     primary key (name, kind, time)
 );
+
+With name-hash enabled, table also needs the hash column:
+
+alter table metrics add column name_hash int8;
+
+With quantile-points enabled, distributions are also stored in:
+
+create table metric_quantiles
+(
+    name   text        not null,
+    kind   metric_kind not null,
+    time   timestamptz not null,
+    points float8[]    not null,
+    primary key (name, kind, time)
+);
  */
 
+#[derive(Debug, Clone, Default)]
+pub struct PostgreSQLOptions {
+    pub name_hash: bool,
+    /// Also emits `name.delta` and `name.cumulative` rows for counters.
+    pub cumulative_counters: bool,
+    /// Number of consecutive failed publishes after which the backend is unhealthy.
+    pub failure_threshold: u32,
+    /// Number of quantile points stored per statistics, see [`quantile_points`].
+    pub quantile_points: Option<usize>,
+    pub retry: Retry,
+}
+
+/// How a failed operation is retried, where the delay doubles after every attempt.
+#[derive(Debug, Clone)]
+pub struct Retry {
+    /// Number of attempts after the first one.
+    pub retries: u32,
+    pub base_delay: Duration,
+    /// Total time spent retrying, no attempt is started once it would be exceeded.
+    pub max_time: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            base_delay: Duration::from_millis(100),
+            max_time: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Retry {
+    /// Result of the first successful attempt, or error of the last one.
+    fn run<T, E: std::fmt::Display>(&self, mut f: impl FnMut() -> Result<T, E>) -> Result<T, E> {
+        let started = Instant::now();
+        let mut delay = self.base_delay;
+        let mut attempt = 0;
+
+        loop {
+            let err = match f() {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+
+            if attempt >= self.retries || started.elapsed() + delay > self.max_time {
+                return Err(err);
+            }
+
+            attempt += 1;
+
+            log::warn!(
+                "Attempt {attempt} of {} failed, retrying in {delay:?}: {err}",
+                self.retries + 1
+            );
+
+            thread::sleep(delay);
+
+            delay = delay.saturating_mul(2);
+        }
+    }
+}
+
+/// Counts consecutive failures, where a single success resets the count.
+#[derive(Debug)]
+struct Health {
+    threshold: u32,
+    consecutive_failures: u32,
+}
+
+impl Health {
+    fn new(threshold: u32) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            consecutive_failures: 0,
+        }
+    }
+
+    fn record(&mut self, success: bool) {
+        if success {
+            self.consecutive_failures = 0;
+        } else {
+            self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.consecutive_failures < self.threshold
+    }
+}
+
+/// Connection that can tell whether it was closed, like after the server restarted.
+pub trait Connection {
+    fn is_closed(&self) -> bool;
+}
+
+impl Connection for postgres::Client {
+    fn is_closed(&self) -> bool {
+        postgres::Client::is_closed(self)
+    }
+}
+
+pub type Connect<C> = Box<dyn FnMut() -> Result<C, String> + Send>;
+
+/// Connection that is opened again through `connect` once it's found closed, instead of failing
+/// until the daemon is restarted.
+pub struct Reconnecting<C> {
+    connection: Option<C>,
+    connect: Connect<C>,
+}
+
+impl<C: Connection> Reconnecting<C> {
+    pub fn new(connection: C, connect: Connect<C>) -> Self {
+        Self {
+            connection: Some(connection),
+            connect,
+        }
+    }
+
+    /// Open connection, which is a new one when the previous one was closed.
+    fn get(&mut self) -> Result<&mut C, String> {
+        if self
+            .connection
+            .as_ref()
+            .is_some_and(|connection| connection.is_closed())
+        {
+            self.connection = None;
+        }
+
+        if self.connection.is_none() {
+            log::warn!("Connection is closed, reconnecting");
+
+            match (self.connect)() {
+                Ok(connection) => {
+                    log::info!("Reconnected successfully");
+
+                    self.connection = Some(connection);
+                }
+                Err(err) => {
+                    log::error!("Reconnecting failed: {err}");
+
+                    return Err(err);
+                }
+            }
+        }
+
+        self.connection
+            .as_mut()
+            .ok_or_else(|| "Connection is not open".to_string())
+    }
+}
+
 pub struct PostgreSQL {
-    client: postgres::Client,
+    client: Reconnecting<postgres::Client>,
+    options: Options,
+    postgresql_options: PostgreSQLOptions,
+    health: Health,
 }
 
 impl Debug for PostgreSQL {
@@ -78,7 +621,7 @@ impl Debug for PostgreSQL {
     }
 }
 
-#[derive(Debug, ToSql)]
+#[derive(Debug, Clone, Copy, PartialEq, ToSql)]
 #[postgres(name = "metric_kind")]
 enum MetricKind {
     #[postgres(name = "gauge")]
@@ -89,123 +632,1903 @@ enum MetricKind {
     Timing,
 }
 
+fn rows(
+    time_frame: &TimeFrame,
+    options: &Options,
+    postgresql_options: &PostgreSQLOptions,
+) -> Vec<(MetricKind, String, f64)> {
+    let mut rows = vec![];
+
+    time_frame
+        .gauges
+        .iter()
+        .for_each(|(name, value)| rows.push((MetricKind::Gauge, name.clone(), *value)));
+
+    time_frame
+        .sets
+        .iter()
+        .for_each(|(name, value)| rows.push((MetricKind::Gauge, name.clone(), *value as f64)));
+
+    time_frame.counters.iter().for_each(|(name, stats)| {
+        counter_fields(stats, options)
+            .into_iter()
+            .for_each(|(field, value)| {
+                rows.push((MetricKind::Counter, format!("{name}.{field}"), value))
+            });
+
+        if postgresql_options.cumulative_counters {
+            rows.push((MetricKind::Counter, format!("{name}.delta"), stats.sum()));
+            rows.push((
+                MetricKind::Counter,
+                format!("{name}.cumulative"),
+                time_frame
+                    .cumulative_counters
+                    .get(name)
+                    .copied()
+                    .unwrap_or_default(),
+            ));
+        }
+    });
+
+    time_frame.timings.iter().for_each(|(name, stats)| {
+        timing_fields(stats, options)
+            .into_iter()
+            .for_each(|(field, value)| {
+                rows.push((MetricKind::Timing, format!("{name}.{field}"), value))
+            });
+    });
+
+    time_frame.summaries.iter().for_each(|(name, stats)| {
+        statistics_fields(stats, options)
+            .into_iter()
+            .for_each(|(field, value)| {
+                rows.push((MetricKind::Gauge, format!("{name}.{field}"), value))
+            });
+    });
+
+    time_frame.histograms.iter().for_each(|(name, histogram)| {
+        histogram_fields(histogram)
+            .into_iter()
+            .for_each(|(field, value)| {
+                rows.push((MetricKind::Counter, format!("{name}.{field}"), value))
+            });
+    });
+
+    time_frame
+        .derived
+        .iter()
+        .for_each(|(name, value)| rows.push((MetricKind::Gauge, name.clone(), *value)));
+
+    rows
+}
+
+/// PostgreSQL accepts NaN and infinity in `float8`, but they are meaningless as a metric value,
+/// so they are stored as `NULL` instead.
+fn sanitize(name: &str, value: f64) -> Option<f64> {
+    if value.is_finite() {
+        Some(value)
+    } else {
+        log::warn!("Metric {name} has non-finite value {value}, storing NULL instead");
+
+        None
+    }
+}
+
+/// 64 bit FNV-1a hash of the name, which is the same across runs and platforms.
+fn name_hash(name: &str) -> i64 {
+    name.bytes().fold(0xcbf29ce484222325, |hash: u64, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    }) as i64
+}
+
 impl PostgreSQL {
-    pub fn new(client: postgres::Client) -> Self {
-        Self { client }
+    pub fn new(
+        client: Reconnecting<postgres::Client>,
+        options: Options,
+        postgresql_options: PostgreSQLOptions,
+    ) -> Self {
+        Self {
+            client,
+            options,
+            health: Health::new(postgresql_options.failure_threshold),
+            postgresql_options,
+        }
     }
+}
 
-    fn insert(&mut self, time: &DateTime<Utc>, metric_kind: MetricKind, name: &str, value: f64) {
-        let sql = r"
-insert into metrics (name, kind, time, value)
-values ($1, $2, $3, $4)
-on conflict (name, kind, time)
-    do nothing
-";
+/// Values at `count` evenly spaced quantiles from `0` to `1`, so the first point is min and the
+/// last one is max. Any percentile can be approximated from them by linear interpolation between
+/// the two closest points. At least two points are returned, unless there are no samples.
+pub fn quantile_points(stats: &Statistics, count: usize) -> Vec<f64> {
+    let count = count.max(2);
 
-        if let Err(err) = self
-            .client
-            .execute(sql, &[&name, &metric_kind, time, &value])
-        {
-            log::error!("{err}");
+    (0..count)
+        .filter_map(|i| {
+            stats.percentile(
+                Percentile::try_from(i as f64 / (count - 1) as f64)
+                    .expect("Evenly spaced quantiles are within range"),
+            )
+        })
+        .collect()
+}
+
+/// Quantile points of every statistics with samples, where timing points are in the timing
+/// resolution.
+fn quantile_rows(
+    time_frame: &TimeFrame,
+    options: &Options,
+    count: usize,
+) -> Vec<(MetricKind, String, Vec<f64>)> {
+    [
+        (MetricKind::Counter, &time_frame.counters, 1.),
+        (
+            MetricKind::Timing,
+            &time_frame.timings,
+            options.timing_resolution.nanoseconds(),
+        ),
+        (MetricKind::Gauge, &time_frame.summaries, 1.),
+    ]
+    .into_iter()
+    .flat_map(|(metric_kind, statistics, unit)| {
+        statistics
+            .iter()
+            .filter(|(_, stats)| stats.count() > 0)
+            .map(move |(name, stats)| {
+                let points = quantile_points(stats, count)
+                    .into_iter()
+                    .map(|point| point / unit)
+                    .collect();
+
+                (metric_kind, name.clone(), points)
+            })
+    })
+    .collect()
+}
+
+/// Rows inserted by a single statement, which keeps number of parameters well below the limit
+/// of 65535.
+const ROWS_PER_STATEMENT: usize = 1000;
+
+/// Multi-row insert statement, where `$1` is the time shared by all rows.
+fn insert_sql(rows: usize, name_hash: bool) -> String {
+    let columns = if name_hash { 4 } else { 3 };
+
+    let values = (0..rows)
+        .map(|row| {
+            let first = 2 + row * columns;
+
+            if name_hash {
+                format!(
+                    "(${}, ${}, ${}, $1, ${})",
+                    first,
+                    first + 1,
+                    first + 2,
+                    first + 3
+                )
+            } else {
+                format!("(${}, ${}, $1, ${})", first, first + 1, first + 2)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        "insert into metrics (name, {}kind, time, value) values {values} on conflict (name, kind, time) do nothing",
+        if name_hash { "name_hash, " } else { "" }
+    )
+}
+
+/// Inserts rows and quantile rows in a single transaction.
+fn insert(
+    client: &mut postgres::Client,
+    time: &DateTime<Utc>,
+    rows: &[(MetricKind, i64, String, Option<f64>)],
+    quantile_rows: &[(MetricKind, String, Vec<f64>)],
+    name_hash_enabled: bool,
+) -> Result<(), postgres::Error> {
+    let mut transaction = client.transaction()?;
+
+    for chunk in rows.chunks(ROWS_PER_STATEMENT) {
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![time];
+
+        for (metric_kind, hash, name, value) in chunk {
+            params.push(name);
+
+            if name_hash_enabled {
+                params.push(hash);
+            }
+
+            params.push(metric_kind);
+            params.push(value);
         }
+
+        transaction.execute(&insert_sql(chunk.len(), name_hash_enabled), &params)?;
+    }
+
+    for chunk in quantile_rows.chunks(ROWS_PER_STATEMENT) {
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![time];
+
+        for (metric_kind, name, points) in chunk {
+            params.push(name);
+            params.push(metric_kind);
+            params.push(points);
+        }
+
+        transaction.execute(&insert_quantiles_sql(chunk.len()), &params)?;
     }
+
+    transaction.commit()
+}
+
+/// Multi-row insert statement of quantile points, where `$1` is the time shared by all rows.
+fn insert_quantiles_sql(rows: usize) -> String {
+    let values = (0..rows)
+        .map(|row| {
+            let first = 2 + row * 3;
+
+            format!("(${}, ${}, $1, ${})", first, first + 1, first + 2)
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        "insert into metric_quantiles (name, kind, time, points) values {values} on conflict (name, kind, time) do nothing"
+    )
 }
 
 impl Backend for PostgreSQL {
-    fn publish(&mut self, time: &DateTime<Utc>, time_frame: &TimeFrame) {
+    fn publish(&mut self, time: &DateTime<Utc>, time_frame: &TimeFrame) -> Result<(), String> {
+        let name_hash_enabled = self.postgresql_options.name_hash;
+        let rows = rows(time_frame, &self.options, &self.postgresql_options)
+            .into_iter()
+            .map(|(metric_kind, name, value)| {
+                let value = sanitize(&name, value);
+
+                (metric_kind, name_hash(&name), name, value)
+            })
+            .collect::<Vec<_>>();
+        let quantile_rows = self
+            .postgresql_options
+            .quantile_points
+            .map(|count| quantile_rows(time_frame, &self.options, count))
+            .unwrap_or_default();
+
+        let client = &mut self.client;
+
+        // Connection is checked on every attempt, so a retry can use a new one
+        let result = self
+            .postgresql_options
+            .retry
+            .run(|| {
+                insert(
+                    client.get()?,
+                    time,
+                    &rows,
+                    &quantile_rows,
+                    name_hash_enabled,
+                )
+                .map_err(|err| err.to_string())
+            })
+            .map_err(|err| format!("Inserting {} rows failed: {err}", rows.len()));
+
+        self.health.record(result.is_ok());
+
+        result
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.health.is_healthy()
+    }
+}
+
+pub type Series = Vec<(DateTime<Utc>, String, Option<f64>)>;
+
+/// Time, kind and value of every stored row with the given name in `[from, to)`, ordered by time.
+pub fn query(
+    client: &mut postgres::Client,
+    name: &str,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+) -> Result<Series, String> {
+    let sql = r"
+select time, kind::text, value
+from metrics
+where name = $1
+  and time >= $2
+  and time < $3
+order by time, kind
+";
+
+    client
+        .query(sql, &[&name, from, to])
+        .map(|rows| {
+            rows.iter()
+                .map(|row| (row.get(0), row.get(1), row.get(2)))
+                .collect()
+        })
+        .map_err(|err| err.to_string())
+}
+
+/// Renders series as CSV with `time,kind,value` header, where `NULL` values are left empty.
+pub fn render_series(series: &Series) -> String {
+    let mut lines = vec!["time,kind,value".to_string()];
+
+    series.iter().for_each(|(time, kind, value)| {
+        lines.push(format!(
+            "{},{kind},{}",
+            time.to_rfc3339(),
+            value.map_or_else(String::new, |value| value.to_string())
+        ))
+    });
+
+    lines.join("\n")
+}
+
+/// Connect, read and write timeout of TCP backends, so a server that stops responding fails the
+/// publish instead of holding the backend forever.
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connects to the first of the addresses `address` resolves to that accepts the connection.
+fn connect(address: &str) -> io::Result<TcpStream> {
+    let mut last_err = None;
+
+    for address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, SOCKET_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(SOCKET_TIMEOUT))?;
+                stream.set_write_timeout(Some(SOCKET_TIMEOUT))?;
+
+                return Ok(stream);
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+    }))
+}
+
+/// Sends metrics over TCP in Graphite plaintext format, `<path> <value> <timestamp>` per line.
+/// Connection is kept between publishes and established again after a failed write.
+pub struct Graphite {
+    address: String,
+    options: Options,
+    stream: Option<TcpStream>,
+}
+
+impl Debug for Graphite {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Graphite {{ address: {:?} }}", self.address)
+    }
+}
+
+impl Graphite {
+    pub fn new(address: String, options: Options) -> Self {
+        Self {
+            address,
+            options,
+            stream: None,
+        }
+    }
+
+    fn render(&self, time: &DateTime<Utc>, time_frame: &TimeFrame) -> String {
+        let timestamp = time.timestamp();
+        let mut lines = vec![];
+
         time_frame
             .gauges
             .iter()
-            .for_each(|(name, value)| self.insert(time, MetricKind::Gauge, name, *value as f64));
+            .for_each(|(name, value)| lines.push(format!("{name} {value} {timestamp}\n")));
 
-        time_frame.counters.iter().for_each(|(name, stats)| {
-            self.insert(
-                time,
-                MetricKind::Counter,
-                &format!("{name}.count"),
-                stats.count() as f64,
-            );
-            self.insert(
-                time,
-                MetricKind::Counter,
-                &format!("{name}.sum"),
-                stats.sum() as f64,
-            );
-            self.insert(
-                time,
-                MetricKind::Counter,
-                &format!("{name}.avg"),
-                stats.average(),
-            );
-            self.insert(
-                time,
-                MetricKind::Counter,
-                &format!("{name}.std"),
-                stats.std(),
-            );
-            self.insert(
-                time,
-                MetricKind::Counter,
-                &format!("{name}.median"),
-                stats.median(),
-            );
-            self.insert(
-                time,
-                MetricKind::Counter,
-                &format!("{name}.p75"),
-                stats.percentile(0.75) as f64,
-            );
-            self.insert(
-                time,
-                MetricKind::Counter,
-                &format!("{name}.p90"),
-                stats.percentile(0.90) as f64,
-            );
+        time_frame
+            .sets
+            .iter()
+            .for_each(|(name, value)| lines.push(format!("{name} {value} {timestamp}\n")));
+
+        type Fields = fn(&Statistics, &Options) -> Vec<(String, f64)>;
+
+        for (statistics, fields) in [
+            (&time_frame.counters, counter_fields as Fields),
+            (&time_frame.timings, timing_fields),
+            (&time_frame.summaries, statistics_fields),
+        ] {
+            statistics.iter().for_each(|(name, stats)| {
+                fields(stats, &self.options)
+                    .into_iter()
+                    .for_each(|(field, value)| {
+                        lines.push(format!("{name}.{field} {value} {timestamp}\n"))
+                    });
+            });
+        }
+
+        time_frame.histograms.iter().for_each(|(name, histogram)| {
+            histogram_fields(histogram)
+                .into_iter()
+                .for_each(|(field, value)| {
+                    lines.push(format!("{name}.{field} {value} {timestamp}\n"))
+                });
         });
 
-        time_frame.timings.iter().for_each(|(name, stats)| {
-            self.insert(
-                time,
-                MetricKind::Timing,
-                &format!("{name}.count"),
-                stats.count() as f64,
-            );
-            self.insert(
-                time,
-                MetricKind::Timing,
-                &format!("{name}.sum"),
-                stats.sum() as f64,
-            );
-            self.insert(
-                time,
-                MetricKind::Timing,
-                &format!("{name}.avg"),
-                stats.average(),
-            );
-            self.insert(
-                time,
-                MetricKind::Timing,
-                &format!("{name}.std"),
-                stats.std(),
-            );
-            self.insert(
-                time,
-                MetricKind::Timing,
-                &format!("{name}.median"),
-                stats.median(),
-            );
-            self.insert(
-                time,
-                MetricKind::Timing,
-                &format!("{name}.p75"),
-                stats.percentile(0.75) as f64,
-            );
-            self.insert(
-                time,
-                MetricKind::Timing,
-                &format!("{name}.p90"),
-                stats.percentile(0.90) as f64,
+        time_frame
+            .derived
+            .iter()
+            .for_each(|(name, value)| lines.push(format!("{name} {value} {timestamp}\n")));
+
+        lines.concat()
+    }
+}
+
+impl Backend for Graphite {
+    fn publish(&mut self, time: &DateTime<Utc>, time_frame: &TimeFrame) -> Result<(), String> {
+        let payload = self.render(time, time_frame);
+
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => self.stream.insert(
+                connect(&self.address)
+                    .map_err(|err| format!("Unable to connect to {}: {err}", self.address))?,
+            ),
+        };
+
+        if let Err(err) = stream.write_all(payload.as_bytes()) {
+            self.stream = None;
+
+            return Err(format!("Unable to write to {}: {err}", self.address));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct InfluxOptions {
+    pub org: String,
+    /// Bucket, or `database/retention-policy` for InfluxDB 1.x.
+    pub bucket: String,
+    pub token: Option<String>,
+}
+
+/// Writes metrics to InfluxDB in line protocol through its `/api/v2/write` HTTP endpoint, which
+/// InfluxDB 1.8 and later provide as well. Every metric is a measurement tagged with its kind,
+/// where gauges, sets and derived metrics have a single `value` field and statistics have a field
+/// per statistic. Timings are in the configured timing resolution. Only plain HTTP is supported.
+pub struct Influx {
+    /// Address connected to, like `127.0.0.1:8086`.
+    address: String,
+    /// Request target, including the query string.
+    target: String,
+    token: Option<String>,
+    options: Options,
+}
+
+impl Debug for Influx {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Influx {{ address: {:?}, target: {:?} }}",
+            self.address, self.target
+        )
+    }
+}
+
+/// Escapes `special` characters, which are `,` and ` ` in measurements, and `=` as well in field
+/// keys.
+fn escape_line_protocol(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+fn encode_query_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+impl Influx {
+    /// Fails when `url` is not a plain HTTP URL, like `http://127.0.0.1:8086`.
+    pub fn new(url: &str, influx_options: InfluxOptions, options: Options) -> Result<Self, String> {
+        let Some(rest) = url.strip_prefix("http://") else {
+            return Err(format!(
+                "InfluxDB URL {url} is not supported, only http:// URLs are"
+            ));
+        };
+
+        let (authority, base) = rest.split_once('/').unwrap_or((rest, ""));
+
+        if authority.is_empty() {
+            return Err(format!("InfluxDB URL {url} has no host"));
+        }
+
+        let address = if authority.contains(':') {
+            authority.to_string()
+        } else {
+            format!("{authority}:80")
+        };
+
+        let base = base.trim_end_matches('/');
+        let base = if base.is_empty() {
+            String::new()
+        } else {
+            format!("/{base}")
+        };
+
+        Ok(Self {
+            address,
+            target: format!(
+                "{base}/api/v2/write?org={}&bucket={}&precision=ns",
+                encode_query_value(&influx_options.org),
+                encode_query_value(&influx_options.bucket)
+            ),
+            token: influx_options.token,
+            options,
+        })
+    }
+
+    fn render(&self, time: &DateTime<Utc>, time_frame: &TimeFrame) -> String {
+        let timestamp = time.timestamp_nanos_opt().unwrap_or_default();
+        let mut lines = vec![];
+
+        let mut push = |name: &str, kind: &str, fields: Vec<(String, f64)>| {
+            let fields = fields
+                .into_iter()
+                .filter(|(field, value)| {
+                    if !value.is_finite() {
+                        log::warn!(
+                            "Metric {name} has non-finite {field} value {value}, dropping the field"
+                        );
+                    }
+
+                    value.is_finite()
+                })
+                .map(|(field, value)| {
+                    format!("{}={value}", escape_line_protocol(&field, &[',', '=', ' ']))
+                })
+                .collect::<Vec<_>>();
+
+            if fields.is_empty() {
+                return;
+            }
+
+            lines.push(format!(
+                "{},kind={kind} {} {timestamp}\n",
+                escape_line_protocol(name, &[',', ' ']),
+                fields.join(",")
+            ));
+        };
+
+        for (kind, values) in [
+            ("gauge", &time_frame.gauges),
+            ("derived", &time_frame.derived),
+        ] {
+            values
+                .iter()
+                .for_each(|(name, value)| push(name, kind, vec![("value".to_string(), *value)]));
+        }
+
+        time_frame.sets.iter().for_each(|(name, value)| {
+            push(name, "set", vec![("value".to_string(), *value as f64)])
+        });
+
+        type Fields = fn(&Statistics, &Options) -> Vec<(String, f64)>;
+
+        for (kind, statistics, fields) in [
+            ("counter", &time_frame.counters, counter_fields as Fields),
+            ("timing", &time_frame.timings, timing_fields),
+            ("summary", &time_frame.summaries, statistics_fields),
+        ] {
+            statistics
+                .iter()
+                .for_each(|(name, stats)| push(name, kind, fields(stats, &self.options)));
+        }
+
+        time_frame
+            .histograms
+            .iter()
+            .for_each(|(name, histogram)| push(name, "histogram", histogram_fields(histogram)));
+
+        lines.concat()
+    }
+
+    fn request(&self, body: &str) -> String {
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.target,
+            self.address,
+            body.len()
+        );
+
+        if let Some(token) = &self.token {
+            request.push_str(&format!("Authorization: Token {token}\r\n"));
+        }
+
+        request.push_str("\r\n");
+        request.push_str(body);
+
+        request
+    }
+}
+
+impl Backend for Influx {
+    fn publish(&mut self, time: &DateTime<Utc>, time_frame: &TimeFrame) -> Result<(), String> {
+        let body = self.render(time, time_frame);
+
+        if body.is_empty() {
+            return Ok(());
+        }
+
+        let mut stream = connect(&self.address)
+            .map_err(|err| format!("Unable to connect to {}: {err}", self.address))?;
+
+        stream
+            .write_all(self.request(&body).as_bytes())
+            .map_err(|err| format!("Unable to write to {}: {err}", self.address))?;
+
+        let mut response = String::new();
+
+        std::io::Read::read_to_string(&mut stream, &mut response)
+            .map_err(|err| format!("Unable to read response from {}: {err}", self.address))?;
+
+        let status = response.lines().next().unwrap_or_default();
+
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(format!(
+                "InfluxDB at {} responded with {status:?}: {}",
+                self.address,
+                response
+                    .split_once("\r\n\r\n")
+                    .map_or("", |(_, body)| body.trim())
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::{Added, Metric, Registry};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    struct Sleeping {
+        delay: Duration,
+        published: Arc<AtomicBool>,
+    }
+
+    impl Backend for Sleeping {
+        fn publish(&mut self, _: &DateTime<Utc>, _: &TimeFrame) -> Result<(), String> {
+            thread::sleep(self.delay);
+
+            self.published.store(true, Ordering::SeqCst);
+
+            Ok(())
+        }
+    }
+
+    struct Failing;
+
+    struct Panicking;
+
+    impl Backend for Panicking {
+        fn publish(&mut self, _: &DateTime<Utc>, _: &TimeFrame) -> Result<(), String> {
+            panic!("Backend bug")
+        }
+    }
+
+    impl Backend for Failing {
+        fn publish(&mut self, _: &DateTime<Utc>, _: &TimeFrame) -> Result<(), String> {
+            Err("connection refused".to_string())
+        }
+    }
+
+    struct Recording {
+        time: Arc<Mutex<Option<DateTime<Utc>>>>,
+    }
+
+    impl Backend for Recording {
+        fn publish(&mut self, time: &DateTime<Utc>, _: &TimeFrame) -> Result<(), String> {
+            *self.time.lock().unwrap() = Some(*time);
+
+            Ok(())
+        }
+    }
+
+    fn shared(backend: impl Backend + 'static) -> Shared {
+        Arc::new(Mutex::new(Box::new(backend)))
+    }
+
+    fn percentiles(values: &[f64]) -> Vec<Percentile> {
+        values
+            .iter()
+            .map(|value| Percentile::try_from(*value).unwrap())
+            .collect()
+    }
+
+    fn time_frame(metrics: Vec<Metric>) -> TimeFrame {
+        let mut registry = Registry::default();
+
+        for metric in metrics {
+            assert_eq!(Added::Aggregated, registry.add(&metric));
+        }
+
+        registry.finalize()
+    }
+
+    #[test]
+    fn percentiles_are_omitted_below_min_samples() {
+        let time_frame = time_frame(vec![
+            Metric {
+                name: "abc".into(),
+                kind: crate::metrics::MetricKind::Counter(2.),
+            },
+            Metric {
+                name: "abc".into(),
+                kind: crate::metrics::MetricKind::Counter(4.),
+            },
+        ]);
+
+        let options = Options {
+            min_samples_for_percentiles: 3,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            vec![
+                (MetricKind::Counter, "abc.count".to_string(), 2.),
+                (MetricKind::Counter, "abc.sum".to_string(), 6.),
+                (MetricKind::Counter, "abc.avg".to_string(), 3.),
+                (MetricKind::Counter, "abc.std".to_string(), 2f64.sqrt()),
+                (MetricKind::Counter, "abc.min".to_string(), 2.),
+                (MetricKind::Counter, "abc.max".to_string(), 4.),
+            ],
+            rows(&time_frame, &options, &PostgreSQLOptions::default())
+        );
+
+        let options = Options {
+            min_samples_for_percentiles: 2,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            vec![
+                (MetricKind::Counter, "abc.count".to_string(), 2.),
+                (MetricKind::Counter, "abc.sum".to_string(), 6.),
+                (MetricKind::Counter, "abc.avg".to_string(), 3.),
+                (MetricKind::Counter, "abc.std".to_string(), 2f64.sqrt()),
+                (MetricKind::Counter, "abc.min".to_string(), 2.),
+                (MetricKind::Counter, "abc.max".to_string(), 4.),
+                (MetricKind::Counter, "abc.median".to_string(), 3.),
+                (MetricKind::Counter, "abc.p75".to_string(), 4.),
+                (MetricKind::Counter, "abc.p90".to_string(), 4.),
+            ],
+            rows(&time_frame, &options, &PostgreSQLOptions::default())
+        );
+    }
+
+    #[test]
+    fn counters_emit_delta_and_cumulative_rows() {
+        let postgresql_options = PostgreSQLOptions {
+            cumulative_counters: true,
+            ..Default::default()
+        };
+        let counter = |value| Metric {
+            name: "abc".into(),
+            kind: crate::metrics::MetricKind::Counter(value),
+        };
+        let delta_and_cumulative = |time_frame: &TimeFrame| {
+            rows(time_frame, &Options::default(), &postgresql_options)
+                .into_iter()
+                .filter(|(_, name, _)| name == "abc.delta" || name == "abc.cumulative")
+                .collect::<Vec<_>>()
+        };
+
+        let mut registry = Registry::new(crate::metrics::Options {
+            cumulative_counters: true,
+            ..Default::default()
+        });
+        assert_eq!(Added::Aggregated, registry.add(&counter(2.)));
+        assert_eq!(Added::Aggregated, registry.add(&counter(3.)));
+
+        let mut next = registry.new_with_carryover();
+
+        assert_eq!(
+            vec![
+                (MetricKind::Counter, "abc.delta".to_string(), 5.),
+                (MetricKind::Counter, "abc.cumulative".to_string(), 5.),
+            ],
+            delta_and_cumulative(&registry.finalize())
+        );
+
+        assert_eq!(Added::Aggregated, next.add(&counter(4.)));
+
+        assert_eq!(
+            vec![
+                (MetricKind::Counter, "abc.delta".to_string(), 4.),
+                (MetricKind::Counter, "abc.cumulative".to_string(), 9.),
+            ],
+            delta_and_cumulative(&next.finalize())
+        );
+    }
+
+    #[test]
+    fn console_omits_percentiles_below_min_samples() {
+        let time_frame = time_frame(vec![Metric {
+            name: "abc".into(),
+            kind: crate::metrics::MetricKind::Timing(
+                5,
+                crate::metrics::TimerResolution::NanoSeconds,
+                1,
+            ),
+        }]);
+
+        let time = DateTime::from_timestamp(0, 0).unwrap();
+
+        let console = Console::new(
+            Options {
+                min_samples_for_percentiles: 2,
+                ..Default::default()
+            },
+            ConsoleOptions::default(),
+        );
+
+        assert_eq!(
+            "1970-01-01T00:00:00+00:00
+Timings:
+  abc
+    count: 1
+    sum: 5
+    avg: 5
+    std: 0
+    min: 5
+    max: 5",
+            console.render(&time, &time_frame)
+        );
+    }
+
+    #[test]
+    fn timings_are_displayed_in_configured_resolution() {
+        let time_frame = time_frame(vec![Metric {
+            name: "abc".into(),
+            kind: crate::metrics::MetricKind::Timing(
+                5,
+                crate::metrics::TimerResolution::Seconds,
+                1,
+            ),
+        }]);
+
+        let time = DateTime::from_timestamp(0, 0).unwrap();
+
+        let options = Options {
+            percentiles: percentiles(&[0.9]),
+            skip_std: true,
+            timing_resolution: TimerResolution::Seconds,
+            ..Default::default()
+        };
+
+        assert_eq!(Some(5.), value(&time_frame, &options, "abc.median"));
+        assert_eq!(
+            Some(5_000_000_000.),
+            value(&time_frame, &Options::default(), "abc.median")
+        );
+
+        let console = Console::new(options, ConsoleOptions::default());
+
+        assert_eq!(
+            "1970-01-01T00:00:00+00:00
+Timings:
+  abc
+    count: 1
+    sum: 5
+    avg: 5
+    min: 5
+    max: 5
+    median: 5
+    p90: 5",
+            console.render(&time, &time_frame)
+        );
+    }
+
+    #[test]
+    fn known_metrics_without_samples_are_published_as_zero() {
+        let mut registry = Registry::new(crate::metrics::Options {
+            known_counters: vec!["abc".into(), "def".into()],
+            known_timings: vec!["ghi".into()],
+            ..Default::default()
+        });
+
+        assert_eq!(
+            Added::Aggregated,
+            registry.add(&Metric {
+                name: "def".into(),
+                kind: crate::metrics::MetricKind::Counter(3.),
+            })
+        );
+
+        let time_frame = registry.finalize();
+
+        let options = Options {
+            skip_std: true,
+            percentiles: vec![],
+            window: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+
+        let mut rows = rows(&time_frame, &options, &PostgreSQLOptions::default());
+
+        rows.sort_by(|(_, a, _), (_, b, _)| a.cmp(b));
+
+        assert_eq!(
+            vec![
+                (MetricKind::Counter, "abc.count".to_string(), 0.),
+                (MetricKind::Counter, "abc.rate".to_string(), 0.),
+                (MetricKind::Counter, "abc.sum".to_string(), 0.),
+                (MetricKind::Counter, "def.avg".to_string(), 3.),
+                (MetricKind::Counter, "def.count".to_string(), 1.),
+                (MetricKind::Counter, "def.max".to_string(), 3.),
+                (MetricKind::Counter, "def.median".to_string(), 3.),
+                (MetricKind::Counter, "def.min".to_string(), 3.),
+                (MetricKind::Counter, "def.rate".to_string(), 0.05),
+                (MetricKind::Counter, "def.sum".to_string(), 3.),
+                (MetricKind::Timing, "ghi.count".to_string(), 0.),
+                (MetricKind::Timing, "ghi.sum".to_string(), 0.),
+            ],
+            rows
+        );
+        assert!(quantile_rows(&time_frame, &options, 3)
+            .iter()
+            .all(|(_, name, _)| name == "def"));
+    }
+
+    #[test]
+    fn counters_are_published_with_rate_over_window() {
+        let time_frame = time_frame(vec![
+            Metric {
+                name: "abc".into(),
+                kind: crate::metrics::MetricKind::Counter(20.),
+            },
+            Metric {
+                name: "abc".into(),
+                kind: crate::metrics::MetricKind::Counter(10.),
+            },
+            Metric {
+                name: "def".into(),
+                kind: crate::metrics::MetricKind::Timing(
+                    5,
+                    crate::metrics::TimerResolution::NanoSeconds,
+                    1,
+                ),
+            },
+        ]);
+
+        let options = Options {
+            min_samples_for_percentiles: 3,
+            skip_std: true,
+            window: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            vec![
+                (MetricKind::Counter, "abc.count".to_string(), 2.),
+                (MetricKind::Counter, "abc.sum".to_string(), 30.),
+                (MetricKind::Counter, "abc.avg".to_string(), 15.),
+                (MetricKind::Counter, "abc.min".to_string(), 10.),
+                (MetricKind::Counter, "abc.max".to_string(), 20.),
+                (MetricKind::Counter, "abc.rate".to_string(), 0.5),
+            ],
+            rows(&time_frame, &options, &PostgreSQLOptions::default())
+                .into_iter()
+                .filter(|(metric_kind, _, _)| *metric_kind == MetricKind::Counter)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(0.5), value(&time_frame, &options, "abc.rate"));
+        assert_eq!(None, value(&time_frame, &options, "def.rate"));
+
+        let console = Console::new(options, ConsoleOptions::default());
+        let rendered = console.render(&DateTime::from_timestamp(0, 0).unwrap(), &time_frame);
+
+        assert!(rendered.contains("  abc\n    count: 2\n    sum: 30\n    avg: 15\n    min: 10\n    max: 20\n    rate: 0.5"));
+        assert_eq!(1, rendered.matches("rate:").count());
+    }
+
+    #[test]
+    fn histogram_buckets_are_published_with_bounds() {
+        let mut registry = Registry::new(crate::metrics::Options {
+            histogram_buckets: vec![0.5, 2.],
+            ..Default::default()
+        });
+
+        for value in [0.25, 0.5, 1.] {
+            assert_eq!(
+                Added::Aggregated,
+                registry.add(&Metric {
+                    name: "abc".into(),
+                    kind: crate::metrics::MetricKind::Histogram(value),
+                })
             );
+        }
+
+        let time_frame = registry.finalize();
+
+        assert_eq!(
+            vec![
+                (MetricKind::Counter, "abc.count".to_string(), 3.),
+                (MetricKind::Counter, "abc.sum".to_string(), 1.75),
+                (MetricKind::Counter, "abc.bucket.le_0_5".to_string(), 2.),
+                (MetricKind::Counter, "abc.bucket.le_2".to_string(), 3.),
+                (MetricKind::Counter, "abc.bucket.le_inf".to_string(), 3.),
+            ],
+            rows(
+                &time_frame,
+                &Options::default(),
+                &PostgreSQLOptions::default()
+            )
+        );
+        assert_eq!(
+            Some(2.),
+            value(&time_frame, &Options::default(), "abc.bucket.le_0_5")
+        );
+    }
+
+    #[test]
+    fn set_cardinality_is_published_as_integer() {
+        let time_frame = time_frame(
+            ["alice", "bob", "alice"]
+                .into_iter()
+                .map(|value| Metric {
+                    name: "users".into(),
+                    kind: crate::metrics::MetricKind::Set(value.into()),
+                })
+                .collect(),
+        );
+
+        assert_eq!(
+            vec![(MetricKind::Gauge, "users".to_string(), 2.)],
+            rows(
+                &time_frame,
+                &Options::default(),
+                &PostgreSQLOptions::default()
+            )
+        );
+        assert!(Console::default()
+            .render(&DateTime::default(), &time_frame)
+            .ends_with("Sets:\n  users - 2"));
+        assert_eq!(
+            "users 2 0\n",
+            Graphite::new(String::new(), Options::default())
+                .render(&DateTime::default(), &time_frame)
+        );
+    }
+
+    #[test]
+    fn console_renders_time_in_configured_format() {
+        let time = DateTime::from_timestamp(1_700_000_000, 123_000_000).unwrap();
+
+        for (timestamp_format, expected) in [
+            (TimestampFormat::Rfc3339, "2023-11-14T22:13:20.123+00:00"),
+            (TimestampFormat::UnixSeconds, "1700000000"),
+            (TimestampFormat::UnixMillis, "1700000000123"),
+        ] {
+            let console = Console::new(Options::default(), ConsoleOptions { timestamp_format });
+
+            assert_eq!(expected, console.render(&time, &time_frame(vec![])));
+        }
+    }
+
+    #[test]
+    fn backend_exceeding_timeout_does_not_block_others() {
+        let slow = Arc::new(AtomicBool::new(false));
+        let fast = Arc::new(AtomicBool::new(false));
+
+        let start = Instant::now();
+
+        let outcomes = publish_all(
+            &[
+                (
+                    "slow".to_string(),
+                    shared(Sleeping {
+                        delay: Duration::from_secs(2),
+                        published: slow.clone(),
+                    }),
+                    Some(Duration::from_millis(50)),
+                ),
+                (
+                    "fast".to_string(),
+                    shared(Sleeping {
+                        delay: Duration::ZERO,
+                        published: fast.clone(),
+                    }),
+                    Some(Duration::from_secs(1)),
+                ),
+            ],
+            &Utc::now(),
+            Arc::new(time_frame(vec![])),
+            None,
+            &ThreadBudget::default(),
+        );
+
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            vec![
+                ("slow".to_string(), Outcome::TimedOut),
+                ("fast".to_string(), Outcome::Published)
+            ],
+            outcomes
+        );
+        assert!(!slow.load(Ordering::SeqCst));
+        assert!(fast.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn slow_backend_does_not_delay_others() {
+        let slow = Arc::new(AtomicBool::new(false));
+        let fast = Arc::new(AtomicBool::new(false));
+        let fast_published = Arc::new(Mutex::new(None));
+
+        struct Timed {
+            published: Arc<AtomicBool>,
+            published_at: Arc<Mutex<Option<Instant>>>,
+        }
+
+        impl Backend for Timed {
+            fn publish(&mut self, _: &DateTime<Utc>, _: &TimeFrame) -> Result<(), String> {
+                *self.published_at.lock().unwrap() = Some(Instant::now());
+
+                self.published.store(true, Ordering::SeqCst);
+
+                Ok(())
+            }
+        }
+
+        let start = Instant::now();
+
+        let outcomes = publish_all(
+            &[
+                (
+                    "slow".to_string(),
+                    shared(Sleeping {
+                        delay: Duration::from_millis(500),
+                        published: slow.clone(),
+                    }),
+                    None,
+                ),
+                (
+                    "fast".to_string(),
+                    shared(Timed {
+                        published: fast.clone(),
+                        published_at: fast_published.clone(),
+                    }),
+                    None,
+                ),
+            ],
+            &Utc::now(),
+            Arc::new(time_frame(vec![])),
+            None,
+            &ThreadBudget::default(),
+        );
+
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert!(fast_published.lock().unwrap().unwrap() - start < Duration::from_millis(250));
+        assert_eq!(
+            vec![
+                ("slow".to_string(), Outcome::Published),
+                ("fast".to_string(), Outcome::Published)
+            ],
+            outcomes
+        );
+        assert!(slow.load(Ordering::SeqCst));
+        assert!(fast.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn backend_still_publishing_is_skipped_as_busy() {
+        let publishers = [(
+            "slow".to_string(),
+            shared(Sleeping {
+                delay: Duration::from_millis(500),
+                published: Arc::new(AtomicBool::new(false)),
+            }),
+            Some(Duration::from_millis(50)),
+        )];
+
+        let publish = || {
+            publish_all(
+                &publishers,
+                &Utc::now(),
+                Arc::new(time_frame(vec![])),
+                None,
+                &ThreadBudget::default(),
+            )
+        };
+
+        assert_eq!(vec![("slow".to_string(), Outcome::TimedOut)], publish());
+        assert_eq!(vec![("slow".to_string(), Outcome::Busy)], publish());
+    }
+
+    #[test]
+    fn panicked_backend_is_reported_as_failed() {
+        let outcomes = publish_all(
+            &[
+                ("panicking".to_string(), shared(Panicking), None),
+                (
+                    "timed".to_string(),
+                    shared(Panicking),
+                    Some(Duration::from_secs(1)),
+                ),
+            ],
+            &Utc::now(),
+            Arc::new(time_frame(vec![])),
+            None,
+            &ThreadBudget::default(),
+        );
+
+        assert!(outcomes
+            .iter()
+            .all(|(_, outcome)| matches!(outcome, Outcome::Failed(_))));
+    }
+
+    #[test]
+    fn backends_over_thread_budget_are_shed() {
+        let fast = Arc::new(AtomicBool::new(false));
+
+        let outcomes = publish_all(
+            &[
+                (
+                    "slow".to_string(),
+                    shared(Sleeping {
+                        delay: Duration::from_millis(500),
+                        published: Arc::new(AtomicBool::new(false)),
+                    }),
+                    Some(Duration::from_millis(50)),
+                ),
+                (
+                    "fast".to_string(),
+                    shared(Sleeping {
+                        delay: Duration::ZERO,
+                        published: fast.clone(),
+                    }),
+                    None,
+                ),
+            ],
+            &Utc::now(),
+            Arc::new(time_frame(vec![])),
+            None,
+            &ThreadBudget::new(Some(1)),
+        );
+
+        assert_eq!(
+            vec![
+                ("slow".to_string(), Outcome::TimedOut),
+                ("fast".to_string(), Outcome::Shed)
+            ],
+            outcomes
+        );
+        assert!(!fast.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn backends_receive_cycle_boundary_time() {
+        let recorded = Arc::new(Mutex::new(None));
+        let boundary = Utc::now();
+
+        publish_all(
+            &[
+                (
+                    "slow".to_string(),
+                    shared(Sleeping {
+                        delay: Duration::from_millis(50),
+                        published: Arc::new(AtomicBool::new(false)),
+                    }),
+                    None,
+                ),
+                (
+                    "recording".to_string(),
+                    shared(Recording {
+                        time: recorded.clone(),
+                    }),
+                    None,
+                ),
+            ],
+            &boundary,
+            Arc::new(time_frame(vec![])),
+            Some(Duration::from_millis(10)),
+            &ThreadBudget::default(),
+        );
+
+        assert_eq!(Some(boundary), *recorded.lock().unwrap());
+        assert!(boundary < Utc::now());
+    }
+
+    #[test]
+    fn series_is_rendered_as_csv() {
+        let series = vec![
+            (
+                DateTime::from_timestamp(0, 0).unwrap(),
+                "counter".to_string(),
+                Some(2.5),
+            ),
+            (
+                DateTime::from_timestamp(10, 0).unwrap(),
+                "counter".to_string(),
+                None,
+            ),
+            (
+                DateTime::from_timestamp(20, 0).unwrap(),
+                "gauge".to_string(),
+                Some(-3.),
+            ),
+        ];
+
+        assert_eq!(
+            "time,kind,value
+1970-01-01T00:00:00+00:00,counter,2.5
+1970-01-01T00:00:10+00:00,counter,
+1970-01-01T00:00:20+00:00,gauge,-3",
+            render_series(&series)
+        );
+    }
+
+    #[test]
+    fn health_flips_only_after_consecutive_failures() {
+        let mut health = Health::new(3);
+
+        health.record(false);
+        health.record(false);
+        assert!(health.is_healthy());
+
+        health.record(true);
+        health.record(false);
+        health.record(false);
+        assert!(health.is_healthy());
+
+        health.record(false);
+        assert!(!health.is_healthy());
+
+        health.record(true);
+        assert!(health.is_healthy());
+    }
+
+    #[test]
+    fn failed_insert_is_retried_with_backoff() {
+        let retry = Retry {
+            retries: 3,
+            base_delay: Duration::from_millis(10),
+            max_time: Duration::from_secs(1),
+        };
+        let mut attempts = 0;
+
+        let started = Instant::now();
+
+        let result = retry.run(|| {
+            attempts += 1;
+
+            if attempts < 2 {
+                Err("connection reset")
+            } else {
+                Ok(attempts)
+            }
+        });
+
+        assert_eq!(Ok(2), result);
+        assert!(started.elapsed() >= Duration::from_millis(10));
+
+        let mut attempts = 0;
+
+        let result: Result<(), _> = retry.run(|| {
+            attempts += 1;
+
+            Err("connection reset")
+        });
+
+        assert_eq!(Err("connection reset"), result);
+        assert_eq!(4, attempts);
+
+        // Next delay of 40 ms would exceed max time after waiting 10 and 20 ms
+        let retry = Retry {
+            max_time: Duration::from_millis(50),
+            ..retry
+        };
+        let mut attempts = 0;
+
+        let result: Result<(), _> = retry.run(|| {
+            attempts += 1;
+
+            Err("connection reset")
         });
+
+        assert!(result.is_err());
+        assert_eq!(3, attempts);
+    }
+
+    #[test]
+    fn closed_connection_is_replaced_by_a_new_one() {
+        struct Fake {
+            id: usize,
+            closed: bool,
+        }
+
+        impl Connection for Fake {
+            fn is_closed(&self) -> bool {
+                self.closed
+            }
+        }
+
+        let connects = Arc::new(Mutex::new(vec![
+            Ok(Fake {
+                id: 2,
+                closed: false,
+            }),
+            Err("connection refused".to_string()),
+        ]));
+
+        let mut connection = Reconnecting::new(
+            Fake {
+                id: 0,
+                closed: false,
+            },
+            Box::new(move || connects.lock().unwrap().pop().unwrap()),
+        );
+
+        let mut publish = || {
+            let fake = connection.get()?;
+
+            if fake.id == 0 {
+                // Server went away, so the connection is closed after the error
+                fake.closed = true;
+
+                Err(format!("connection {} reset", fake.id))
+            } else {
+                Ok(fake.id)
+            }
+        };
+
+        assert_eq!(Err("connection 0 reset".to_string()), publish());
+        assert_eq!(Err("connection refused".to_string()), publish());
+        assert_eq!(Ok(2), publish());
+        assert_eq!(Ok(2), publish());
+    }
+
+    #[test]
+    fn insert_statement_covers_all_rows() {
+        assert_eq!(
+            "insert into metrics (name, kind, time, value) values ($2, $3, $1, $4), ($5, $6, $1, $7) \
+            on conflict (name, kind, time) do nothing",
+            insert_sql(2, false)
+        );
+        assert_eq!(
+            "insert into metrics (name, name_hash, kind, time, value) values ($2, $3, $4, $1, $5) \
+            on conflict (name, kind, time) do nothing",
+            insert_sql(1, true)
+        );
+    }
+
+    #[test]
+    fn rows_of_many_metrics_are_inserted_with_few_statements() {
+        let time_frame = time_frame(
+            (0..500)
+                .map(|i| Metric {
+                    name: format!("metric{i}"),
+                    kind: crate::metrics::MetricKind::Counter(i as f64),
+                })
+                .collect(),
+        );
+
+        let rows = rows(
+            &time_frame,
+            &Options::default(),
+            &PostgreSQLOptions::default(),
+        );
+
+        assert_eq!(4500, rows.len());
+        assert_eq!(5, rows.chunks(ROWS_PER_STATEMENT).count());
+    }
+
+    #[test]
+    fn graphite_sends_plaintext_lines() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut graphite = Graphite::new(
+            listener.local_addr().unwrap().to_string(),
+            Options {
+                min_samples_for_percentiles: 2,
+                ..Default::default()
+            },
+        );
+
+        graphite
+            .publish(
+                &DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+                &time_frame(vec![
+                    Metric {
+                        name: "abc".into(),
+                        kind: crate::metrics::MetricKind::Gauge(GaugeOperation::Set(-3.)),
+                    },
+                    Metric {
+                        name: "def".into(),
+                        kind: crate::metrics::MetricKind::Counter(4.),
+                    },
+                ]),
+            )
+            .unwrap();
+
+        drop(graphite);
+
+        let mut received = String::new();
+        std::io::Read::read_to_string(&mut listener.accept().unwrap().0, &mut received).unwrap();
+
+        assert_eq!(
+            "abc -3 1700000000
+def.count 1 1700000000
+def.sum 4 1700000000
+def.avg 4 1700000000
+def.std 0 1700000000
+def.min 4 1700000000
+def.max 4 1700000000
+",
+            received
+        );
+    }
+
+    #[test]
+    fn graphite_reports_connection_failure() {
+        let address = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+            listener.local_addr().unwrap().to_string()
+        };
+
+        let mut graphite = Graphite::new(address, Options::default());
+
+        assert!(graphite
+            .publish(&Utc::now(), &time_frame(vec![]))
+            .unwrap_err()
+            .starts_with("Unable to connect"));
+    }
+
+    fn influx(url: &str) -> Influx {
+        Influx::new(
+            url,
+            InfluxOptions {
+                org: "my org".to_string(),
+                bucket: "metrics".to_string(),
+                token: Some("secret".to_string()),
+            },
+            Options {
+                min_samples_for_percentiles: 2,
+                skip_std: true,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn influx_renders_line_protocol() {
+        let time_frame = time_frame(vec![
+            Metric {
+                name: "cpu load".into(),
+                kind: crate::metrics::MetricKind::Gauge(GaugeOperation::Set(1.5)),
+            },
+            Metric {
+                name: "requests".into(),
+                kind: crate::metrics::MetricKind::Counter(4.),
+            },
+            Metric {
+                name: "db,query".into(),
+                kind: crate::metrics::MetricKind::Timing(
+                    2,
+                    crate::metrics::TimerResolution::MicroSeconds,
+                    1,
+                ),
+            },
+            Metric {
+                name: "users".into(),
+                kind: crate::metrics::MetricKind::Set("alice".into()),
+            },
+        ]);
+
+        let mut lines = influx("http://127.0.0.1:8086")
+            .render(
+                &DateTime::from_timestamp(1_700_000_000, 5).unwrap(),
+                &time_frame,
+            )
+            .lines()
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+
+        lines.sort();
+
+        assert_eq!(
+            vec![
+                "cpu\\ load,kind=gauge value=1.5 1700000000000000005",
+                "db\\,query,kind=timing count=1,sum=2000,avg=2000,min=2000,max=2000 1700000000000000005",
+                "requests,kind=counter count=1,sum=4,avg=4,min=4,max=4 1700000000000000005",
+                "users,kind=set value=1 1700000000000000005",
+            ],
+            lines
+        );
+    }
+
+    #[test]
+    fn influx_posts_to_write_endpoint() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            ["204 No Content", "400 Bad Request"]
+                .into_iter()
+                .map(|status| {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let mut request = vec![0; 4096];
+                    let size = std::io::Read::read(&mut stream, &mut request).unwrap();
+
+                    stream
+                        .write_all(
+                            format!("HTTP/1.1 {status}\r\nContent-Length: 7\r\n\r\ninvalid")
+                                .as_bytes(),
+                        )
+                        .unwrap();
+
+                    String::from_utf8_lossy(&request[..size]).to_string()
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let mut influx = influx(&format!("http://{address}/influx/"));
+        let time_frame = time_frame(vec![Metric {
+            name: "abc".into(),
+            kind: crate::metrics::MetricKind::Gauge(GaugeOperation::Set(2.)),
+        }]);
+        let time = DateTime::from_timestamp(1, 0).unwrap();
+
+        assert!(influx.publish(&time, &time_frame).is_ok());
+        assert_eq!(
+            "InfluxDB at ".to_string()
+                + &address.to_string()
+                + " responded with \"HTTP/1.1 400 Bad Request\": invalid",
+            influx.publish(&time, &time_frame).unwrap_err()
+        );
+
+        let requests = server.join().unwrap();
+
+        assert!(requests[0].starts_with(
+            "POST /influx/api/v2/write?org=my%20org&bucket=metrics&precision=ns HTTP/1.1\r\n"
+        ));
+        assert!(requests[0].contains("\r\nAuthorization: Token secret\r\n"));
+        assert!(requests[0].ends_with("\r\n\r\nabc,kind=gauge value=2 1000000000\n"));
+    }
+
+    #[test]
+    fn influx_requires_http_url() {
+        assert!(Influx::new(
+            "https://127.0.0.1:8086",
+            InfluxOptions::default(),
+            Options::default()
+        )
+        .is_err());
+        assert_eq!(
+            "127.0.0.1:80",
+            Influx::new(
+                "http://127.0.0.1",
+                InfluxOptions::default(),
+                Options::default()
+            )
+            .unwrap()
+            .address
+        );
+    }
+
+    #[test]
+    fn configured_percentiles_are_published() {
+        let time_frame = time_frame(
+            (1..=1000)
+                .map(|value| Metric {
+                    name: "abc".into(),
+                    kind: crate::metrics::MetricKind::Counter(value as f64),
+                })
+                .collect(),
+        );
+
+        let options = Options {
+            percentiles: percentiles(&[0.5, 0.95, 0.999]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            vec![
+                ("abc.p50".to_string(), 501.),
+                ("abc.p95".to_string(), 951.),
+                ("abc.p99_9".to_string(), 1000.),
+            ],
+            rows(&time_frame, &options, &PostgreSQLOptions::default())
+                .into_iter()
+                .filter(|(_, name, _)| name.starts_with("abc.p"))
+                .map(|(_, name, value)| (name, value))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn std_is_omitted_when_skipped() {
+        let time_frame = time_frame(vec![
+            Metric {
+                name: "abc".into(),
+                kind: crate::metrics::MetricKind::Counter(2.),
+            },
+            Metric {
+                name: "abc".into(),
+                kind: crate::metrics::MetricKind::Counter(4.),
+            },
+        ]);
+
+        let options = Options {
+            min_samples_for_percentiles: 3,
+            skip_std: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            vec![
+                (MetricKind::Counter, "abc.count".to_string(), 2.),
+                (MetricKind::Counter, "abc.sum".to_string(), 6.),
+                (MetricKind::Counter, "abc.avg".to_string(), 3.),
+                (MetricKind::Counter, "abc.min".to_string(), 2.),
+                (MetricKind::Counter, "abc.max".to_string(), 4.),
+            ],
+            rows(&time_frame, &options, &PostgreSQLOptions::default())
+        );
+    }
+
+    #[test]
+    fn quantile_points_reconstruct_percentiles() {
+        let time_frame = time_frame(
+            (1..=1000)
+                .map(|value| Metric {
+                    name: "abc".into(),
+                    kind: crate::metrics::MetricKind::Counter(value as f64),
+                })
+                .collect(),
+        );
+        let stats = &time_frame.counters["abc"];
+
+        let points = quantile_points(stats, 8);
+
+        assert_eq!(8, points.len());
+        assert_eq!(stats.min(), Some(points[0]));
+        assert_eq!(stats.max(), Some(points[7]));
+
+        let reconstruct = |p: f64| {
+            let position = p * (points.len() - 1) as f64;
+            let (lower, upper) = (position.floor() as usize, position.ceil() as usize);
+
+            points[lower] + (points[upper] - points[lower]) * position.fract()
+        };
+
+        for p in [0.5, 0.9] {
+            let percentile = Percentile::try_from(p).unwrap();
+
+            assert!((reconstruct(p) - stats.percentile(percentile).unwrap()).abs() <= 0.01 * 1000.);
+        }
+
+        assert_eq!(
+            vec![(MetricKind::Counter, "abc".to_string(), points)],
+            quantile_rows(&time_frame, &Options::default(), 8)
+        );
+        assert_eq!(
+            "insert into metric_quantiles (name, kind, time, points) values ($2, $3, $1, $4), ($5, $6, $1, $7) on conflict (name, kind, time) do nothing",
+            insert_quantiles_sql(2)
+        );
+    }
+
+    #[test]
+    fn derived_ratio_is_published() {
+        let counter = |name: &str, value| Metric {
+            name: name.into(),
+            kind: crate::metrics::MetricKind::Counter(value),
+        };
+        let mut time_frame = time_frame(vec![
+            counter("errors", 1.),
+            counter("requests", 1.),
+            counter("requests", 1.),
+            counter("requests", 1.),
+            counter("requests", 1.),
+        ]);
+
+        let derived = vec![crate::derived::Derived {
+            name: "error_rate".to_string(),
+            expression: "errors.count / requests.count"
+                .to_string()
+                .try_into()
+                .unwrap(),
+        }];
+
+        time_frame.derived = crate::derived::evaluate(&derived, |reference| {
+            value(&time_frame, &Options::default(), reference)
+        });
+
+        assert!(rows(
+            &time_frame,
+            &Options::default(),
+            &PostgreSQLOptions::default()
+        )
+        .contains(&(MetricKind::Gauge, "error_rate".to_string(), 0.25)));
+        assert!(Graphite::new(String::new(), Options::default())
+            .render(&DateTime::default(), &time_frame)
+            .contains("error_rate 0.25 0\n"));
+    }
+
+    #[test]
+    fn non_finite_values_are_sanitized() {
+        assert_eq!(Some(1.5), sanitize("abc", 1.5));
+        assert_eq!(None, sanitize("abc", f64::NAN));
+        assert_eq!(None, sanitize("abc", f64::INFINITY));
+        assert_eq!(None, sanitize("abc", f64::NEG_INFINITY));
+    }
+
+    #[test]
+    fn name_hash_is_stable() {
+        assert_eq!(0xcbf29ce484222325u64 as i64, name_hash(""));
+        assert_eq!(0xaf63dc4c8601ec8cu64 as i64, name_hash("a"));
+        assert_eq!(0x85944171f73967e8u64 as i64, name_hash("foobar"));
+        assert_eq!(name_hash("api.requests"), name_hash("api.requests"));
+        assert_ne!(name_hash("api.requests"), name_hash("api.request"));
+    }
+
+    #[test]
+    fn self_test_fails_only_for_required_backends() {
+        let console = || -> Constructed {
+            Ok(Box::new(Console::new(
+                Options::default(),
+                ConsoleOptions::default(),
+            )))
+        };
+
+        assert_eq!(
+            Ok(()),
+            self_test(
+                vec![
+                    ("console".to_string(), console(), true),
+                    ("failing".to_string(), Ok(Box::new(Failing)), false),
+                    ("missing".to_string(), Err("unreachable".to_string()), false),
+                ],
+                "metco"
+            )
+        );
+
+        assert_eq!(
+            Err("Self-test of required backend failing failed: connection refused".to_string()),
+            self_test(
+                vec![
+                    ("console".to_string(), console(), true),
+                    ("failing".to_string(), Ok(Box::new(Failing)), true),
+                ],
+                "metco"
+            )
+        );
+
+        assert_eq!(
+            Err("Self-test of required backend missing failed: unreachable".to_string()),
+            self_test(
+                vec![("missing".to_string(), Err("unreachable".to_string()), true)],
+                "metco"
+            )
+        );
     }
 }
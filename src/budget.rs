@@ -0,0 +1,129 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Bounds the number of threads running at once. Permit is held by the spawned thread until it
+/// finishes, so threads that outlive their caller, like timed out publishes, still count.
+#[derive(Debug, Clone, Default)]
+pub struct ThreadBudget {
+    /// Number of threads that can still be spawned, or `None` when there is no limit.
+    available: Option<Arc<AtomicUsize>>,
+}
+
+struct Permit(Arc<AtomicUsize>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+impl ThreadBudget {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            available: limit.map(|limit| Arc::new(AtomicUsize::new(limit))),
+        }
+    }
+
+    fn try_acquire(&self) -> Option<Option<Permit>> {
+        let Some(available) = &self.available else {
+            return Some(None);
+        };
+
+        available
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |available| {
+                available.checked_sub(1)
+            })
+            .ok()
+            .map(|_| Some(Permit(available.clone())))
+    }
+
+    /// Spawns a thread running `f`, or returns `None` without running it when the budget is
+    /// exhausted.
+    pub fn try_spawn<F>(&self, f: F) -> Option<thread::JoinHandle<()>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let permit = self.try_acquire()?;
+
+        Some(thread::spawn(move || {
+            let _permit = permit;
+
+            f()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Barrier;
+    use std::time::Duration;
+
+    #[test]
+    fn active_threads_stay_within_budget() {
+        let budget = ThreadBudget::new(Some(3));
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+
+        let callers = (0..8)
+            .map(|_| {
+                let (budget, active, max_active, spawned, barrier) = (
+                    budget.clone(),
+                    active.clone(),
+                    max_active.clone(),
+                    spawned.clone(),
+                    barrier.clone(),
+                );
+
+                thread::spawn(move || {
+                    barrier.wait();
+
+                    (0..20)
+                        .filter_map(|_| {
+                            let (active, max_active) = (active.clone(), max_active.clone());
+
+                            budget.try_spawn(move || {
+                                let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                                max_active.fetch_max(now, Ordering::SeqCst);
+
+                                thread::sleep(Duration::from_millis(5));
+
+                                active.fetch_sub(1, Ordering::SeqCst);
+                            })
+                        })
+                        .collect::<Vec<_>>()
+                        .into_iter()
+                        .for_each(|handle| {
+                            spawned.fetch_add(1, Ordering::SeqCst);
+                            handle.join().unwrap();
+                        });
+                })
+            })
+            .collect::<Vec<_>>();
+
+        callers
+            .into_iter()
+            .for_each(|caller| caller.join().unwrap());
+
+        assert!(max_active.load(Ordering::SeqCst) <= 3);
+        assert!(spawned.load(Ordering::SeqCst) < 8 * 20);
+        assert!(budget.try_spawn(|| {}).is_some());
+    }
+
+    #[test]
+    fn unlimited_budget_always_spawns() {
+        let budget = ThreadBudget::default();
+
+        let handles = (0..16)
+            .map(|_| budget.try_spawn(|| thread::sleep(Duration::from_millis(5))))
+            .collect::<Option<Vec<_>>>()
+            .unwrap();
+
+        handles
+            .into_iter()
+            .for_each(|handle| handle.join().unwrap());
+    }
+}
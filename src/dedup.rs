@@ -0,0 +1,80 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+/// Remembers SipHash (64 bit) of datagrams seen within the window, bounded by capacity, where
+/// the oldest hashes are forgotten first. Window is measured from the first time payload was
+/// seen, so a client retransmitting the same payload forever is not silenced forever.
+#[derive(Debug)]
+pub struct Deduplicator {
+    window: Duration,
+    capacity: usize,
+    seen: HashSet<u64>,
+    order: VecDeque<(u64, Instant)>,
+}
+
+impl Deduplicator {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            window,
+            capacity,
+            seen: HashSet::default(),
+            order: VecDeque::default(),
+        }
+    }
+
+    pub fn is_duplicate(&mut self, payload: &[u8], now: Instant) -> bool {
+        while let Some((hash, seen_at)) = self.order.front() {
+            if now.duration_since(*seen_at) < self.window && self.order.len() < self.capacity {
+                break;
+            }
+
+            self.seen.remove(hash);
+            self.order.pop_front();
+        }
+
+        let mut hasher = DefaultHasher::new();
+        payload.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        if self.seen.contains(&hash) {
+            return true;
+        }
+
+        self.seen.insert(hash);
+        self.order.push_back((hash, now));
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn same_datagram_within_window_is_duplicate() {
+        let mut deduplicator = Deduplicator::new(Duration::from_secs(1), 16);
+        let now = Instant::now();
+
+        assert!(!deduplicator.is_duplicate(b"abc|c|1", now));
+        assert!(!deduplicator.is_duplicate(b"abc|c|2", now));
+        assert!(deduplicator.is_duplicate(b"abc|c|1", now + Duration::from_millis(500)));
+
+        assert!(!deduplicator.is_duplicate(b"abc|c|1", now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn oldest_datagram_is_forgotten_when_capacity_is_reached() {
+        let mut deduplicator = Deduplicator::new(Duration::from_secs(1), 2);
+        let now = Instant::now();
+
+        assert!(!deduplicator.is_duplicate(b"a|c|1", now));
+        assert!(!deduplicator.is_duplicate(b"b|c|1", now));
+        assert!(!deduplicator.is_duplicate(b"c|c|1", now));
+
+        assert!(deduplicator.is_duplicate(b"c|c|1", now));
+        assert!(!deduplicator.is_duplicate(b"a|c|1", now));
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+
+use nom::branch::alt;
+use nom::bytes::complete::take_while1;
+use nom::character::complete::{char, multispace0, one_of};
+use nom::combinator::{all_consuming, map, verify};
+use nom::multi::many0;
+use nom::number::complete::double;
+use nom::sequence::{delimited, pair, preceded};
+use nom::IResult;
+use serde::Deserialize;
+
+/// Arithmetic expression over published values.
+///
+/// ```text
+/// expression = term (("+" | "-") term)*
+/// term       = factor (("*" | "/") factor)*
+/// factor     = number | reference | "(" expression ")" | "-" factor
+/// reference  = [A-Za-z_] [A-Za-z0-9_.]*
+/// ```
+///
+/// Operators are left associative, and `*` and `/` bind tighter than `+` and `-`. A reference is
+/// a gauge name, a statistics field like `requests.count` or `latency.p95`, or a derived metric
+/// defined earlier in the list.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Expression {
+    Number(f64),
+    Reference(String),
+    Negate(Box<Expression>),
+    Binary(Box<Expression>, char, Box<Expression>),
+}
+
+impl TryFrom<String> for Expression {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        all_consuming(delimited(multispace0, parse_expression, multispace0))(&value)
+            .map(|(_, expression)| expression)
+            .map_err(|_| format!("Expression {value} is not valid"))
+    }
+}
+
+impl Expression {
+    /// Value of the expression, or `None` when a reference is missing, when dividing by zero or
+    /// when the result is otherwise not finite.
+    pub fn evaluate(&self, lookup: &impl Fn(&str) -> Option<f64>) -> Option<f64> {
+        let value = match self {
+            Expression::Number(value) => *value,
+            Expression::Reference(name) => lookup(name)?,
+            Expression::Negate(expression) => -expression.evaluate(lookup)?,
+            Expression::Binary(left, operator, right) => {
+                let left = left.evaluate(lookup)?;
+                let right = right.evaluate(lookup)?;
+
+                match operator {
+                    '+' => left + right,
+                    '-' => left - right,
+                    '*' => left * right,
+                    _ if right == 0. => return None,
+                    _ => left / right,
+                }
+            }
+        };
+
+        value.is_finite().then_some(value)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Derived {
+    pub name: String,
+    pub expression: Expression,
+}
+
+/// Evaluates derived metrics in order, so each one can reference those defined before it.
+/// Metrics whose expression has no value are left out.
+pub fn evaluate(derived: &[Derived], lookup: impl Fn(&str) -> Option<f64>) -> HashMap<String, f64> {
+    let mut values = HashMap::new();
+
+    for Derived { name, expression } in derived {
+        let value = expression
+            .evaluate(&|reference| values.get(reference).copied().or_else(|| lookup(reference)));
+
+        match value {
+            Some(value) => {
+                values.insert(name.clone(), value);
+            }
+            None => log::debug!("Derived metric {name} has no value in this cycle"),
+        }
+    }
+
+    values
+}
+
+fn token<'a, O>(
+    parser: impl FnMut(&'a str) -> IResult<&'a str, O>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+    delimited(multispace0, parser, multispace0)
+}
+
+fn fold(first: Expression, rest: Vec<(char, Expression)>) -> Expression {
+    rest.into_iter().fold(first, |left, (operator, right)| {
+        Expression::Binary(Box::new(left), operator, Box::new(right))
+    })
+}
+
+fn parse_expression(input: &str) -> IResult<&str, Expression> {
+    map(
+        pair(parse_term, many0(pair(token(one_of("+-")), parse_term))),
+        |(first, rest)| fold(first, rest),
+    )(input)
+}
+
+fn parse_term(input: &str) -> IResult<&str, Expression> {
+    map(
+        pair(parse_factor, many0(pair(token(one_of("*/")), parse_factor))),
+        |(first, rest)| fold(first, rest),
+    )(input)
+}
+
+fn parse_factor(input: &str) -> IResult<&str, Expression> {
+    token(alt((
+        map(
+            verify(
+                take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.'),
+                |name: &str| name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_'),
+            ),
+            |name: &str| Expression::Reference(name.to_string()),
+        ),
+        map(double, Expression::Number),
+        delimited(char('('), parse_expression, char(')')),
+        map(preceded(char('-'), parse_factor), |expression| {
+            Expression::Negate(Box::new(expression))
+        }),
+    )))(input)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn expression(value: &str) -> Expression {
+        Expression::try_from(value.to_string()).unwrap()
+    }
+
+    #[test]
+    fn operators_follow_precedence_and_associativity() {
+        let lookup = |_: &str| None;
+
+        assert_eq!(Some(7.), expression("1 + 2 * 3").evaluate(&lookup));
+        assert_eq!(Some(9.), expression("(1 + 2) * 3").evaluate(&lookup));
+        assert_eq!(Some(1.), expression("8 / 4 / 2").evaluate(&lookup));
+        assert_eq!(Some(-4.), expression("1 - 2 - 3").evaluate(&lookup));
+        assert_eq!(Some(-1.), expression("-(3 - 2)").evaluate(&lookup));
+
+        assert!(Expression::try_from("1 +".to_string()).is_err());
+        assert!(Expression::try_from("(1".to_string()).is_err());
+        assert!(Expression::try_from("a b".to_string()).is_err());
+    }
+
+    #[test]
+    fn ratio_is_derived_from_published_values() {
+        let derived = vec![
+            Derived {
+                name: "error_rate".to_string(),
+                expression: expression("errors.count / requests.count"),
+            },
+            Derived {
+                name: "error_percent".to_string(),
+                expression: expression("error_rate * 100"),
+            },
+            Derived {
+                name: "per_worker".to_string(),
+                expression: expression("requests.count / workers"),
+            },
+            Derived {
+                name: "missing".to_string(),
+                expression: expression("unknown.count + 1"),
+            },
+        ];
+
+        let values = evaluate(&derived, |reference| match reference {
+            "errors.count" => Some(5.),
+            "requests.count" => Some(20.),
+            "workers" => Some(0.),
+            _ => None,
+        });
+
+        assert_eq!(
+            HashMap::from([
+                ("error_rate".to_string(), 0.25),
+                ("error_percent".to_string(), 25.)
+            ]),
+            values
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use regex::Regex;
+
+/// Name pattern where `*` matches any sequence of characters, so `api.*` matches every name
+/// starting with `api.`.
+#[derive(Debug)]
+pub struct Glob(Regex);
+
+impl TryFrom<&str> for Glob {
+    type Error = regex::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let pattern = value
+            .split('*')
+            .map(regex::escape)
+            .collect::<Vec<_>>()
+            .join(".*");
+
+        Ok(Self(Regex::new(&format!("^{pattern}$"))?))
+    }
+}
+
+impl Glob {
+    fn matches(&self, name: &str) -> bool {
+        self.0.is_match(name)
+    }
+}
+
+/// Decides which metric names are aggregated. Name must match one of the `allow` patterns, unless
+/// there are none, and must not match any of the `deny` patterns.
+#[derive(Debug, Default)]
+pub struct NameFilter {
+    allow: Vec<Glob>,
+    deny: Vec<Glob>,
+}
+
+impl NameFilter {
+    pub fn new(allow: Vec<Glob>, deny: Vec<Glob>) -> Self {
+        Self { allow, deny }
+    }
+
+    pub fn is_allowed(&self, name: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|glob| glob.matches(name)))
+            && !self.deny.iter().any(|glob| glob.matches(name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::{Added, Metric, MetricKind, Registry};
+
+    fn globs(patterns: &[&str]) -> Vec<Glob> {
+        patterns
+            .iter()
+            .map(|pattern| Glob::try_from(*pattern).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn globs_match_whole_name() {
+        let filter = NameFilter::new(globs(&["api.*", "*.errors", "up"]), vec![]);
+
+        assert!(filter.is_allowed("api.requests"));
+        assert!(filter.is_allowed("db.errors"));
+        assert!(filter.is_allowed("up"));
+        assert!(!filter.is_allowed("api"));
+        assert!(!filter.is_allowed("uptime"));
+        assert!(!filter.is_allowed("db.errors.count"));
+        assert!(filter.is_allowed("api.errors"));
+
+        let filter = NameFilter::new(globs(&["a.b(c)+"]), vec![]);
+
+        assert!(filter.is_allowed("a.b(c)+"));
+        assert!(!filter.is_allowed("axbcc"));
+    }
+
+    #[test]
+    fn denied_names_never_reach_registry() {
+        let filter = NameFilter::new(globs(&["api.*"]), globs(&["api.debug.*"]));
+        let mut registry = Registry::default();
+
+        for name in ["api.requests", "api.debug.cache", "client.1234"] {
+            let metric = Metric {
+                name: name.to_string(),
+                kind: MetricKind::Counter(1.),
+            };
+
+            if filter.is_allowed(&metric.name) {
+                assert_eq!(Added::Aggregated, registry.add(&metric));
+            }
+        }
+
+        let time_frame = registry.finalize();
+
+        assert!(time_frame.counters.contains_key("api.requests"));
+        assert!(!time_frame.counters.contains_key("api.debug.cache"));
+        assert!(!time_frame.counters.contains_key("client.1234"));
+    }
+}
@@ -1,32 +1,89 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::fs;
 use std::io::ErrorKind;
 use std::net::UdpSocket;
-use std::path::PathBuf;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use chrono::Utc;
-use clap::Parser;
-use figment::providers::{Env, Format, Toml};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use figment::providers::{Env, Format, Serialized, Toml};
 use figment::Figment;
-use serde::Deserialize;
+use postgres::config::SslMode;
+use postgres_native_tls::MakeTlsConnector;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
 use stderrlog::Timestamp;
 
-use crate::backend::{Console, PostgreSQL};
-use crate::metrics::Registry;
+use crate::backend::{Console, Graphite, Influx, PostgreSQL};
+use crate::budget::ThreadBudget;
+use crate::dedup::Deduplicator;
+use crate::filter::{Glob, NameFilter};
+use crate::metrics::{
+    Added, GaugeOperation, Metric, MetricKind, Percentile, Registry, TimerResolution,
+};
+use crate::rewrite::Rewriter;
+use crate::snapshot::GaugeSnapshot;
+use crate::spool::Spool;
 
 mod backend;
+mod budget;
+mod dedup;
+mod derived;
+mod filter;
 mod metrics;
 mod protocol;
+mod rewrite;
+mod snapshot;
+mod spool;
+
+#[derive(Deserialize, Debug, Default)]
+enum TimestampFormat {
+    #[default]
+    #[serde(rename = "rfc3339")]
+    Rfc3339,
+    #[serde(rename = "unix")]
+    UnixSeconds,
+    #[serde(rename = "unix-millis")]
+    UnixMillis,
+}
+
+#[derive(Deserialize, Debug, Default)]
+enum TimingResolution {
+    #[serde(rename = "s")]
+    Seconds,
+    #[serde(rename = "ms")]
+    MilliSeconds,
+    #[serde(rename = "us")]
+    MicroSeconds,
+    #[default]
+    #[serde(rename = "ns")]
+    NanoSeconds,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+enum Tls {
+    #[default]
+    #[serde(rename = "disable")]
+    Disable,
+    #[serde(rename = "require")]
+    Require,
+}
 
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type")]
 enum Backend {
     #[serde(rename = "console")]
-    Console,
+    Console {
+        #[serde(rename = "timestamp-format", default)]
+        timestamp_format: TimestampFormat,
+    },
     #[serde(rename = "postgresql")]
     PostgreSQL {
         host: String,
@@ -35,33 +92,299 @@ enum Backend {
         password: String,
         #[serde(rename = "db-name")]
         db_name: String,
+        #[serde(rename = "name-hash", default)]
+        name_hash: bool,
+        #[serde(rename = "cumulative-counters", default)]
+        cumulative_counters: bool,
+        #[serde(rename = "failure-threshold", default = "default_failure_threshold")]
+        failure_threshold: u32,
+        #[serde(rename = "quantile-points")]
+        quantile_points: Option<usize>,
+        #[serde(default)]
+        tls: Tls,
+        #[serde(rename = "ca-cert")]
+        ca_cert: Option<PathBuf>,
+        #[serde(default)]
+        retries: u32,
+        #[serde(
+            rename = "retry-delay",
+            default = "default_retry_delay",
+            with = "humantime_serde"
+        )]
+        retry_delay: Duration,
+        #[serde(
+            rename = "max-retry-time",
+            default = "default_max_retry_time",
+            with = "humantime_serde"
+        )]
+        max_retry_time: Duration,
+    },
+    #[serde(rename = "graphite")]
+    Graphite { host: String, port: u16 },
+    #[serde(rename = "influx")]
+    Influx {
+        url: String,
+        #[serde(default)]
+        org: String,
+        bucket: String,
+        token: Option<String>,
     },
 }
 
+impl Backend {
+    /// String values of the backend which can reference environment variables.
+    fn strings_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            Backend::Console { .. } => vec![],
+            Backend::PostgreSQL {
+                host,
+                user,
+                password,
+                db_name,
+                ..
+            } => vec![host, user, password, db_name],
+            Backend::Graphite { host, .. } => vec![host],
+            Backend::Influx {
+                url,
+                org,
+                bucket,
+                token,
+            } => [url, org, bucket].into_iter().chain(token).collect(),
+        }
+    }
+}
+
+/// Replaces every `${NAME}` in the value with environment variable `NAME` returned by `lookup`.
+/// Value itself is never part of the error, since it's usually a secret.
+fn interpolate(value: &str, lookup: &impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut interpolated = String::new();
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+            return Err("Reference to environment variable is missing closing }".to_string());
+        };
+
+        let name = &rest[start + 2..end];
+
+        interpolated.push_str(&rest[..start]);
+        interpolated.push_str(
+            &lookup(name).ok_or_else(|| format!("Environment variable {name} is not set"))?,
+        );
+
+        rest = &rest[end + 1..];
+    }
+
+    interpolated.push_str(rest);
+
+    Ok(interpolated)
+}
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
+fn default_retry_delay() -> Duration {
+    backend::Retry::default().base_delay
+}
+
+fn default_max_retry_time() -> Duration {
+    backend::Retry::default().max_time
+}
+
+/// TLS connector which trusts system root certificates, along with the one in `ca_cert` if set.
+fn tls_connector(ca_cert: Option<&Path>) -> Result<MakeTlsConnector, Box<dyn Error>> {
+    let mut builder = native_tls::TlsConnector::builder();
+
+    if let Some(ca_cert) = ca_cert {
+        let pem = fs::read(ca_cert)
+            .map_err(|err| format!("Unable to read CA certificate {ca_cert:?}: {err}"))?;
+
+        builder.add_root_certificate(
+            native_tls::Certificate::from_pem(&pem)
+                .map_err(|err| format!("Invalid CA certificate {ca_cert:?}: {err}"))?,
+        );
+    }
+
+    Ok(MakeTlsConnector::new(builder.build()?))
+}
+
+fn connect_postgresql(
+    host: &str,
+    port: u16,
+    user: &str,
+    password: &str,
+    db_name: &str,
+    tls: &Tls,
+    ca_cert: Option<&Path>,
+) -> Result<postgres::Client, Box<dyn Error>> {
+    let mut config = postgres::Config::new();
+
+    config.host(host);
+    config.port(port);
+    config.user(user);
+    config.password(password);
+    config.dbname(db_name);
+
+    Ok(match tls {
+        Tls::Disable => config.connect(postgres::NoTls)?,
+        Tls::Require => {
+            let connector = tls_connector(ca_cert).map_err(|err| {
+                format!("Unable to set up TLS for PostgreSQL at {host}:{port}: {err}")
+            })?;
+
+            config.ssl_mode(SslMode::Require);
+            config.connect(connector)?
+        }
+    })
+}
+
+fn build_backend(
+    backend: &Backend,
+    options: &backend::Options,
+) -> Result<Box<dyn backend::Backend>, Box<dyn Error>> {
+    Ok(match backend {
+        Backend::Console { timestamp_format } => Box::new(Console::new(
+            options.clone(),
+            backend::ConsoleOptions {
+                timestamp_format: match timestamp_format {
+                    TimestampFormat::Rfc3339 => backend::TimestampFormat::Rfc3339,
+                    TimestampFormat::UnixSeconds => backend::TimestampFormat::UnixSeconds,
+                    TimestampFormat::UnixMillis => backend::TimestampFormat::UnixMillis,
+                },
+            },
+        )),
+        Backend::PostgreSQL {
+            host,
+            port,
+            user,
+            password,
+            db_name,
+            name_hash,
+            cumulative_counters,
+            failure_threshold,
+            quantile_points,
+            tls,
+            ca_cert,
+            retries,
+            retry_delay,
+            max_retry_time,
+        } => {
+            let (host, port, user, password, db_name, tls, ca_cert) = (
+                host.clone(),
+                *port,
+                user.clone(),
+                password.clone(),
+                db_name.clone(),
+                tls.clone(),
+                ca_cert.clone(),
+            );
+            let connect = move || {
+                connect_postgresql(
+                    &host,
+                    port,
+                    &user,
+                    &password,
+                    &db_name,
+                    &tls,
+                    ca_cert.as_deref(),
+                )
+                .map_err(|err| err.to_string())
+            };
+
+            Box::new(PostgreSQL::new(
+                backend::Reconnecting::new(connect()?, Box::new(connect)),
+                options.clone(),
+                backend::PostgreSQLOptions {
+                    name_hash: *name_hash,
+                    cumulative_counters: *cumulative_counters,
+                    failure_threshold: *failure_threshold,
+                    quantile_points: *quantile_points,
+                    retry: backend::Retry {
+                        retries: *retries,
+                        base_delay: *retry_delay,
+                        max_time: *max_retry_time,
+                    },
+                },
+            ))
+        }
+        Backend::Graphite { host, port } => {
+            Box::new(Graphite::new(format!("{host}:{port}"), options.clone()))
+        }
+        Backend::Influx {
+            url,
+            org,
+            bucket,
+            token,
+        } => Box::new(Influx::new(
+            url,
+            backend::InfluxOptions {
+                org: org.clone(),
+                bucket: bucket.clone(),
+                token: token.clone(),
+            },
+            options.clone(),
+        )?),
+    })
+}
+
+#[derive(Deserialize, Debug)]
+struct BackendConfig {
+    #[serde(flatten)]
+    backend: Backend,
+    #[serde(rename = "publish-timeout", default, with = "humantime_serde")]
+    publish_timeout: Option<Duration>,
+    #[serde(default)]
+    required: bool,
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(try_from = "BackendsRaw")]
 struct Backends {
-    enabled: Vec<(String, Backend)>,
+    enabled: Vec<(String, BackendConfig)>,
 }
 
 #[derive(Deserialize, Debug)]
 struct BackendsRaw {
     enabled: Vec<String>,
-    available: HashMap<String, Backend>,
+    available: HashMap<String, BackendConfig>,
+    #[serde(rename = "max-backends")]
+    max_backends: Option<usize>,
 }
 
 impl TryFrom<BackendsRaw> for Backends {
     type Error = String;
 
     fn try_from(mut backends: BackendsRaw) -> Result<Self, Self::Error> {
+        if let Some(max_backends) = backends.max_backends {
+            if backends.enabled.len() > max_backends {
+                return Err(format!(
+                    "There are {} backends listed in enabled, but at most {max_backends} are allowed. \
+                    Check if your config was generated correctly or raise max-backends.",
+                    backends.enabled.len()
+                ));
+            }
+        }
+
+        let mut seen = HashSet::new();
+
+        if let Some(backend_name) = backends
+            .enabled
+            .iter()
+            .find(|backend_name| !seen.insert(backend_name.as_str()))
+        {
+            return Err(format!(
+                "Backend {backend_name} is listed multiple times in enabled. Each backend can be enabled only once."
+            ));
+        }
+
         let mut enabled = vec![];
 
         for backend_name in backends.enabled {
             if !backends.available.contains_key(&backend_name) {
                 return Err(format!(
-                    "Backend {backend_name} listed in enabled is not defined as available or is listed multiple times. \
-                    Check if you have [backend.available.{backend_name}] in your config or if you defined same \
-                    backend multiple times as enabled."
+                    "Backend {backend_name} listed in enabled is not defined as available. \
+                    Check if you have [backend.available.{backend_name}] in your config."
                 ));
             }
 
@@ -77,14 +400,541 @@ impl TryFrom<BackendsRaw> for Backends {
     }
 }
 
+impl Backends {
+    /// Resolves references to environment variables, like `password = "${PG_PASSWORD}"`, in
+    /// enabled backends, so secrets can be kept out of the config file.
+    fn interpolate(&mut self, lookup: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        for (backend_name, backend_config) in &mut self.enabled {
+            for value in backend_config.backend.strings_mut() {
+                *value = interpolate(value, &lookup)
+                    .map_err(|err| format!("Backend {backend_name} is not valid. {err}."))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+struct Defaults {
+    counter: u64,
+    timing: Option<u64>,
+    gauge: Option<i64>,
+}
+
+impl Default for Defaults {
+    fn default() -> Self {
+        Self {
+            counter: 1,
+            timing: None,
+            gauge: None,
+        }
+    }
+}
+
+fn default_percentiles() -> Vec<Percentile> {
+    backend::Options::default().percentiles
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(try_from = "Vec<f64>")]
+struct HistogramBuckets(Vec<f64>);
+
+impl TryFrom<Vec<f64>> for HistogramBuckets {
+    type Error = String;
+
+    fn try_from(buckets: Vec<f64>) -> Result<Self, Self::Error> {
+        if let Some(bound) = buckets.iter().find(|bound| !bound.is_finite()) {
+            return Err(format!(
+                "Histogram bucket {bound} is not finite, +Inf bucket is always added"
+            ));
+        }
+
+        if let Some(pair) = buckets.windows(2).find(|pair| pair[0] >= pair[1]) {
+            return Err(format!(
+                "Histogram buckets must be increasing, but {} is followed by {}",
+                pair[0], pair[1]
+            ));
+        }
+
+        Ok(HistogramBuckets(buckets))
+    }
+}
+
+impl Default for HistogramBuckets {
+    fn default() -> Self {
+        Self(metrics::Options::default().histogram_buckets)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct Dedup {
+    #[serde(with = "humantime_serde")]
+    window: Duration,
+    capacity: usize,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct Filter {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct KnownMetrics {
+    counters: Vec<String>,
+    timings: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SpoolConfig {
+    dir: PathBuf,
+    #[serde(rename = "archive-dir")]
+    archive_dir: Option<PathBuf>,
+    #[serde(rename = "poll-interval", with = "humantime_serde")]
+    poll_interval: Duration,
+}
+
+#[derive(Deserialize, Debug)]
+struct Rewrite {
+    pattern: String,
+    replacement: String,
+}
+
+/// Shorter refresh interval is accepted, though flushing that often mostly keeps backends busy.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Refresh interval, which must not be zero since metrics would be flushed in a busy loop.
+fn refresh_interval<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let interval: Duration = humantime_serde::deserialize(deserializer)?;
+
+    if interval.is_zero() {
+        return Err(serde::de::Error::custom(
+            "Refresh interval must be greater than zero",
+        ));
+    }
+
+    if interval < MIN_REFRESH_INTERVAL {
+        log::warn!(
+            "Refresh interval of {interval:?} is below {MIN_REFRESH_INTERVAL:?}, metrics will be flushed very often"
+        );
+    }
+
+    Ok(interval)
+}
+
 #[derive(Deserialize, Debug)]
 struct Config {
     host: String,
     port: u16,
-    #[serde(rename = "refresh-interval", with = "humantime_serde")]
+    #[serde(rename = "refresh-interval", deserialize_with = "refresh_interval")]
     refresh_interval: Duration,
     #[serde(rename = "backend")]
     backends: Backends,
+    #[serde(default)]
+    defaults: Defaults,
+    #[serde(rename = "min-samples-for-percentiles", default)]
+    min_samples_for_percentiles: u64,
+    #[serde(default = "default_percentiles")]
+    percentiles: Vec<Percentile>,
+    #[serde(rename = "skip-std", default)]
+    skip_std: bool,
+    #[serde(rename = "timing-resolution", default)]
+    timing_resolution: TimingResolution,
+    #[serde(rename = "histogram-buckets", default)]
+    histogram_buckets: HistogramBuckets,
+    dedup: Option<Dedup>,
+    #[serde(rename = "trim-names", default)]
+    trim_names: bool,
+    #[serde(rename = "max-name-length")]
+    max_name_length: Option<usize>,
+    #[serde(default)]
+    prefix: String,
+    #[serde(default)]
+    rewrite: Vec<Rewrite>,
+    #[serde(default)]
+    filter: Filter,
+    #[serde(rename = "known-metrics", default)]
+    known_metrics: KnownMetrics,
+    #[serde(default)]
+    derived: Vec<derived::Derived>,
+    #[serde(rename = "run-length-threshold")]
+    run_length_threshold: Option<usize>,
+    #[serde(rename = "expected-samples-per-metric")]
+    expected_samples_per_metric: Option<usize>,
+    #[serde(rename = "timing-reservoir-size")]
+    timing_reservoir_size: Option<NonZeroUsize>,
+    #[serde(
+        rename = "cumulative-counters-idle-cycles",
+        default = "default_cumulative_counters_idle_cycles"
+    )]
+    cumulative_counters_idle_cycles: u32,
+    #[serde(rename = "max-memory")]
+    max_memory: Option<usize>,
+    #[serde(rename = "max-metrics")]
+    max_metrics: Option<usize>,
+    #[serde(rename = "gauge-ttl", default, with = "humantime_serde")]
+    gauge_ttl: Option<Duration>,
+    #[serde(rename = "max-metrics-per-cycle")]
+    max_metrics_per_cycle: Option<u64>,
+    #[serde(rename = "statistics-cycles")]
+    statistics_cycles: Option<u32>,
+    #[serde(rename = "startup-selftest", default)]
+    startup_selftest: bool,
+    spool: Option<SpoolConfig>,
+    #[serde(rename = "skew-warning-threshold", default, with = "humantime_serde")]
+    skew_warning_threshold: Option<Duration>,
+    #[serde(
+        rename = "self-metrics-prefix",
+        default = "default_self_metrics_prefix"
+    )]
+    self_metrics_prefix: String,
+    #[serde(rename = "max-threads")]
+    max_threads: Option<usize>,
+    #[serde(rename = "gauge-snapshot-path")]
+    gauge_snapshot_path: Option<PathBuf>,
+    #[serde(rename = "align-timestamps", default)]
+    align_timestamps: bool,
+    #[serde(
+        rename = "receive-buffer-size",
+        default = "default_receive_buffer_size"
+    )]
+    receive_buffer_size: usize,
+}
+
+fn default_self_metrics_prefix() -> String {
+    "metco".to_string()
+}
+
+fn default_cumulative_counters_idle_cycles() -> u32 {
+    1440
+}
+
+/// Largest UDP payload over IPv4.
+fn default_receive_buffer_size() -> usize {
+    65507
+}
+
+impl Config {
+    fn protocol_options(&self) -> protocol::Options {
+        protocol::Options {
+            counter_default: self.defaults.counter,
+            timing_default: self.defaults.timing,
+            gauge_default: self.defaults.gauge,
+            trim_names: self.trim_names,
+            max_name_length: self.max_name_length,
+            prefix: self.prefix.clone(),
+        }
+    }
+
+    fn rewriter(&self) -> Result<Rewriter, regex::Error> {
+        Ok(Rewriter::new(
+            self.rewrite
+                .iter()
+                .map(|rewrite| Ok((Regex::new(&rewrite.pattern)?, rewrite.replacement.clone())))
+                .collect::<Result<_, regex::Error>>()?,
+        ))
+    }
+
+    fn name_filter(&self) -> Result<NameFilter, regex::Error> {
+        let globs = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| Glob::try_from(pattern.as_str()))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        Ok(NameFilter::new(
+            globs(&self.filter.allow)?,
+            globs(&self.filter.deny)?,
+        ))
+    }
+
+    fn registry_options(&self) -> metrics::Options {
+        metrics::Options {
+            run_length_threshold: self.run_length_threshold,
+            expected_samples_per_metric: self.expected_samples_per_metric,
+            max_memory: self.max_memory,
+            statistics_cycles: self.statistics_cycles,
+            self_metrics_prefix: self.self_metrics_prefix.clone(),
+            max_metrics: self.max_metrics,
+            gauge_ttl: self.gauge_ttl,
+            known_counters: self.known_metrics.counters.clone(),
+            known_timings: self.known_metrics.timings.clone(),
+            timing_reservoir_size: self.timing_reservoir_size,
+            cumulative_counters: self.backends.enabled.iter().any(|(_, backend_config)| {
+                matches!(
+                    backend_config.backend,
+                    Backend::PostgreSQL {
+                        cumulative_counters: true,
+                        ..
+                    }
+                )
+            }),
+            cumulative_counters_idle_cycles: self.cumulative_counters_idle_cycles,
+            histogram_buckets: self.histogram_buckets.0.clone(),
+        }
+    }
+
+    fn backend_options(&self) -> backend::Options {
+        backend::Options {
+            min_samples_for_percentiles: self.min_samples_for_percentiles,
+            percentiles: self.percentiles.clone(),
+            skip_std: self.skip_std,
+            timing_resolution: match self.timing_resolution {
+                TimingResolution::Seconds => TimerResolution::Seconds,
+                TimingResolution::MilliSeconds => TimerResolution::MilliSeconds,
+                TimingResolution::MicroSeconds => TimerResolution::MicroSeconds,
+                TimingResolution::NanoSeconds => TimerResolution::NanoSeconds,
+            },
+            window: Some(self.refresh_interval * self.statistics_cycles.unwrap_or(1)),
+        }
+    }
+}
+
+/// Counts of events in the main loop since the last flush, published as
+/// `<prefix>.internal.<name>` counters, except for `<prefix>.dedup_dropped`.
+#[derive(Debug)]
+struct SelfMetrics {
+    prefix: String,
+    datagrams_received: u64,
+    malformed_lines: u64,
+    overflow_flushes: u64,
+    dedup_dropped: u64,
+}
+
+impl SelfMetrics {
+    fn new(prefix: String) -> Self {
+        Self {
+            prefix,
+            datagrams_received: 0,
+            malformed_lines: 0,
+            overflow_flushes: 0,
+            dedup_dropped: 0,
+        }
+    }
+
+    /// Adds counts to the registry, even when zero apart from dropped duplicates, and starts
+    /// counting again.
+    fn drain_into(&mut self, registry: &mut Registry) {
+        for (name, value) in [
+            ("datagrams_received", &mut self.datagrams_received),
+            ("malformed_lines", &mut self.malformed_lines),
+            ("overflow_flushes", &mut self.overflow_flushes),
+        ] {
+            registry.add(&Metric {
+                name: format!("{}.internal.{name}", self.prefix),
+                kind: MetricKind::Counter(std::mem::take(value) as f64),
+            });
+        }
+
+        if self.dedup_dropped > 0 {
+            registry.add(&Metric {
+                name: format!("{}.dedup_dropped", self.prefix),
+                kind: MetricKind::Counter(std::mem::take(&mut self.dedup_dropped) as f64),
+            });
+        }
+    }
+}
+
+/// Start of the current cycle, along with the number of metrics aggregated since then.
+#[derive(Debug)]
+struct Cycle {
+    started: Instant,
+    added: u64,
+    /// Number of aggregated metrics after which the cycle is flushed early.
+    max_added: Option<u64>,
+    /// With `--once`, the first cycle that should be flushed ends the run instead.
+    once: bool,
+    ended: bool,
+}
+
+impl Cycle {
+    fn new(max_added: Option<u64>) -> Self {
+        Self {
+            started: Instant::now(),
+            added: 0,
+            max_added,
+            once: false,
+            ended: false,
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    fn restart(&mut self) {
+        self.started = Instant::now();
+        self.added = 0;
+    }
+
+    /// Counts an aggregated metric, returning whether the cycle should be flushed early.
+    fn record_added(&mut self) -> bool {
+        self.added += 1;
+
+        self.max_added
+            .is_some_and(|max_added| self.added >= max_added)
+    }
+
+    /// Ends the cycle, returning whether the registry should be flushed right away. With `--once`
+    /// it never is, since the run ends and everything is published in a single flush on exit.
+    fn end(&mut self) -> bool {
+        self.ended = self.once;
+
+        !self.once
+    }
+}
+
+/// Metrics from a parsed text or binary payload, followed by `<prefix>.empty_names_dropped` and
+/// `<prefix>.invalid_names_dropped` counters when some lines were dropped for their name.
+fn parsed_metrics(parsed: protocol::Parsed, self_metrics: &mut SelfMetrics) -> Vec<Metric> {
+    let mut metrics = parsed.metrics;
+
+    self_metrics.malformed_lines += parsed.malformed;
+
+    if parsed.empty_names > 0 {
+        metrics.push(Metric {
+            name: format!("{}.empty_names_dropped", self_metrics.prefix),
+            kind: MetricKind::Counter(parsed.empty_names as f64),
+        });
+    }
+
+    if parsed.invalid_names > 0 {
+        metrics.push(Metric {
+            name: format!("{}.invalid_names_dropped", self_metrics.prefix),
+            kind: MetricKind::Counter(parsed.invalid_names as f64),
+        });
+    }
+
+    metrics
+}
+
+/// Adds metrics describing metco itself, which are published every cycle. Flush duration is the
+/// time it took to aggregate and publish the previous cycle.
+fn add_internal_metrics(
+    registry: &mut Registry,
+    config: &Config,
+    flush_duration: Option<Duration>,
+) {
+    let prefix = &config.self_metrics_prefix;
+
+    registry.add(&Metric {
+        name: format!("{prefix}.refresh_interval_seconds"),
+        kind: MetricKind::Gauge(GaugeOperation::Set(config.refresh_interval.as_secs_f64())),
+    });
+
+    if let Some(flush_duration) = flush_duration {
+        registry.add(&Metric {
+            name: format!("{prefix}.internal.flush_duration_seconds"),
+            kind: MetricKind::Gauge(GaugeOperation::Set(flush_duration.as_secs_f64())),
+        });
+    }
+}
+
+/// Rounds `time` down to the nearest multiple of `interval` since the Unix epoch, so that
+/// consecutive flushes land on boundaries like 12:00:00 and 12:01:00.
+fn align_timestamp(time: DateTime<Utc>, interval: Duration) -> DateTime<Utc> {
+    let interval = interval.as_millis() as i64;
+
+    if interval == 0 {
+        return time;
+    }
+
+    let millis = time.timestamp_millis();
+
+    DateTime::from_timestamp_millis(millis - millis.rem_euclid(interval)).unwrap_or(time)
+}
+
+/// Longest time the main loop blocks on the socket before checking whether shutdown was
+/// requested.
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Binds the socket metrics are received on, with an error that names the address and suggests
+/// the likely cause.
+fn bind(host: &str, port: u16) -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((host, port)).map_err(|err| {
+        let hint = match err.kind() {
+            ErrorKind::AddrInUse => ", is another instance already running?",
+            ErrorKind::AddrNotAvailable => ", host is not an address of this machine",
+            ErrorKind::PermissionDenied => ", ports below 1024 usually require elevated privileges",
+            _ => "",
+        };
+
+        std::io::Error::new(
+            err.kind(),
+            format!("Unable to bind to {host}:{port}: {err}{hint}"),
+        )
+    })?;
+
+    // Receiving always returns to the loop, even if a later timeout can not be set
+    socket.set_read_timeout(Some(SHUTDOWN_CHECK_INTERVAL))?;
+
+    Ok(socket)
+}
+
+/// Receives a single datagram and returns only the bytes that were actually received, along with
+/// whether the datagram was truncated. Buffer holds one byte more than the largest accepted
+/// datagram, so a datagram that fills it up did not fit. Truncated text datagram is cut at its last
+/// complete line, so that a metric cut in the middle is not parsed with a wrong value. Buffer is
+/// reused across datagrams without zeroing, so bytes past the received ones are left from earlier
+/// datagrams and must never be parsed.
+fn receive<'a>(socket: &UdpSocket, buff: &'a mut [u8]) -> std::io::Result<(&'a [u8], bool)> {
+    let size = socket.recv(buff)?;
+    let limit = buff.len() - 1;
+
+    if size <= limit {
+        return Ok((&buff[..size], false));
+    }
+
+    let payload = &buff[..limit];
+
+    if payload.first() == Some(&protocol::BINARY_MAGIC) {
+        return Ok((payload, true));
+    }
+
+    let complete = payload.iter().rposition(|byte| *byte == b'\n').unwrap_or(0);
+
+    Ok((&payload[..complete], true))
+}
+
+/// Loads config, where selected profile's `enabled` list replaces `backend.enabled`. Profile is
+/// taken from the argument, or from `profile` config key (`METCO_PROFILE` env variable).
+fn load_config(figment: Figment, profile: Option<String>) -> Result<Config, Box<figment::Error>> {
+    let profile = profile.or_else(|| {
+        figment
+            .find_value("profile")
+            .ok()
+            .and_then(|value| value.into_string())
+    });
+
+    let figment = match profile {
+        None => figment,
+        Some(profile) => {
+            let enabled = figment
+                .extract_inner::<Vec<String>>(&format!("profiles.{profile}.enabled"))
+                .map_err(|_| {
+                    figment::Error::from(format!(
+                        "Profile {profile} is not defined. Check if you have [profiles.{profile}] \
+                        with enabled list in your config."
+                    ))
+                })?;
+
+            figment.merge(Serialized::default("backend.enabled", enabled))
+        }
+    };
+
+    let mut config: Config = figment.extract().map_err(Box::new)?;
+
+    config
+        .backends
+        .interpolate(|name| std::env::var(name).ok())
+        .map_err(|err| Box::new(figment::Error::from(err)))?;
+
+    Ok(config)
 }
 
 fn init_logging(cli: &CLI) {
@@ -109,122 +959,545 @@ struct CLI {
 
     #[arg(short, long, default_value = "config.toml")]
     config_path: PathBuf,
+
+    #[arg(short, long)]
+    profile: Option<String>,
+
+    /// Validates config and constructs every enabled backend, then exits without receiving
+    /// metrics
+    #[arg(long)]
+    check: bool,
+
+    /// Collects metrics for a single refresh interval, publishes them and exits
+    #[arg(long)]
+    once: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Prints values of a metric stored by an enabled PostgreSQL backend as CSV
+    Query {
+        name: String,
+
+        /// Inclusive start as RFC3339
+        #[arg(long)]
+        from: DateTime<Utc>,
+
+        /// Exclusive end as RFC3339
+        #[arg(long)]
+        to: DateTime<Utc>,
+
+        /// Name of the backend to query, first enabled PostgreSQL backend by default
+        #[arg(short, long)]
+        backend: Option<String>,
+    },
+}
+
+/// Consecutive failed constructions of a backend after which they are logged as errors.
+const CONSTRUCTION_FAILURES_ESCALATED: u32 = 3;
+
+/// Publisher of the backend, or `None` when it could not be constructed. Failure is logged every
+/// time, along with the number of consecutive ones, which is reset once construction succeeds.
+fn construct_publisher(
+    name: &str,
+    backend_config: &BackendConfig,
+    options: &backend::Options,
+    failures: &mut u32,
+) -> Option<backend::Publisher> {
+    match build_backend(&backend_config.backend, options) {
+        Ok(backend) => {
+            if *failures > 0 {
+                log::info!("Backend {name} constructed after {failures} failed attempts");
+            }
+
+            *failures = 0;
+
+            Some((
+                name.to_string(),
+                Arc::new(Mutex::new(backend)),
+                backend_config.publish_timeout,
+            ))
+        }
+        Err(err) => {
+            *failures += 1;
+
+            let level = if *failures >= CONSTRUCTION_FAILURES_ESCALATED {
+                log::Level::Error
+            } else {
+                log::Level::Warn
+            };
+
+            log::log!(
+                level,
+                "Unable to construct backend {name}, skipping it in this cycle ({failures} in a row): {err}"
+            );
+
+            None
+        }
+    }
+}
+
+/// Every enabled backend constructed, which connects to those that need a connection, along with
+/// whether it's required.
+fn construct_backends(config: &Config) -> Vec<(String, backend::Constructed, bool)> {
+    let options = config.backend_options();
+
+    config
+        .backends
+        .enabled
+        .iter()
+        .map(|(name, backend_config)| {
+            (
+                name.clone(),
+                build_backend(&backend_config.backend, &options).map_err(|err| err.to_string()),
+                backend_config.required,
+            )
+        })
+        .collect()
+}
+
+/// Prints whether every enabled backend could be constructed, failing when some could not.
+fn check(config: &Config) -> Result<(), String> {
+    let failed = construct_backends(config)
+        .into_iter()
+        .filter_map(|(name, backend, _)| match backend {
+            Ok(_) => {
+                println!("Backend {name} is ready");
+
+                None
+            }
+            Err(err) => {
+                println!("Backend {name} failed: {err}");
+
+                Some(name)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "Unable to construct backends: {}",
+            failed.join(", ")
+        ))
+    }
+}
+
+fn query(
+    config: &Config,
+    name: &str,
+    from: &DateTime<Utc>,
+    to: &DateTime<Utc>,
+    selected: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let mut client = config
+        .backends
+        .enabled
+        .iter()
+        .filter(|(backend_name, _)| selected.is_none_or(|selected| selected == backend_name))
+        .find_map(|(_, backend_config)| match &backend_config.backend {
+            Backend::PostgreSQL {
+                host,
+                port,
+                user,
+                password,
+                db_name,
+                tls,
+                ca_cert,
+                ..
+            } => Some(connect_postgresql(
+                host,
+                *port,
+                user,
+                password,
+                db_name,
+                tls,
+                ca_cert.as_deref(),
+            )),
+            _ => None,
+        })
+        .ok_or("No enabled PostgreSQL backend to query")??;
+
+    println!(
+        "{}",
+        backend::render_series(&backend::query(&mut client, name, from, to)?)
+    );
+
+    Ok(())
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let cli = CLI::parse();
     init_logging(&cli);
 
-    let config: Arc<Config> = Arc::new(
+    let config: Arc<Config> = Arc::new(load_config(
         Figment::new()
             .merge(Toml::file(cli.config_path))
-            .merge(Env::prefixed("METCO_"))
-            .extract()?,
-    );
+            .merge(Env::prefixed("METCO_")),
+        cli.profile,
+    )?);
 
-    let socket = UdpSocket::bind(format!("{}:{}", config.host, config.port)).unwrap();
+    if cli.check {
+        if let Err(err) = check(&config) {
+            log::error!("{err}");
 
-    let mut now = Instant::now();
+            exit(1);
+        }
 
-    fn flush(registry: Registry, config: Arc<Config>) -> Registry {
-        let new_registry = registry.new_with_gauges();
+        return Ok(());
+    }
 
-        thread::spawn(move || {
-            let backends = config
-                .backends
-                .enabled
-                .iter()
-                .map(|(name, backend)| -> Result<(String, Box<dyn backend::Backend>), Box<dyn Error>> {
-                    Ok(
-                        (
-                            name.clone(),
-                            match backend {
-                                Backend::Console => Box::<Console>::default(),
-                                Backend::PostgreSQL {
-                                    host,
-                                    port,
-                                    user,
-                                    password,
-                                    db_name,
-                                } => Box::new(
-                                    PostgreSQL::new({
-                                        let mut config = postgres::Config::new();
-
-                                        config.host(host);
-                                        config.port(*port);
-                                        config.user(user);
-                                        config.password(password);
-                                        config.dbname(db_name);
-
-                                        config.connect(postgres::NoTls)?
-                                    })
-                                ),
-                            },
-                        )
-                    )
-                })
-                .filter(Result::is_ok)
-                .map(Result::unwrap)
-                .collect::<Vec<_>>();
+    if let Some(Command::Query {
+        name,
+        from,
+        to,
+        backend,
+    }) = &cli.command
+    {
+        return query(&config, name, from, to, backend.as_deref());
+    }
+
+    if config.startup_selftest {
+        if let Err(err) =
+            backend::self_test(construct_backends(&config), &config.self_metrics_prefix)
+        {
+            log::error!("{err}");
+
+            exit(1);
+        }
+    }
+
+    let socket = match bind(&config.host, config.port) {
+        Ok(socket) => socket,
+        Err(err) => {
+            log::error!("{err}");
+
+            exit(1);
+        }
+    };
+
+    let mut cycle = Cycle {
+        once: cli.once,
+        ..Cycle::new(config.max_metrics_per_cycle)
+    };
 
-            log::info!("Aggregating collected metrics");
+    type Flushed = (DateTime<Utc>, Registry);
 
-            let now = Utc::now();
+    /// Starts a thread which owns backends across cycles and publishes every flushed registry
+    /// through them. Backend that failed to be built, or became unhealthy after failing to
+    /// publish, is built again in the next cycle, so that an outage does not disable it
+    /// permanently. Thread stops once the sender is dropped and every flushed registry is
+    /// published.
+    fn start_publisher(config: Arc<Config>) -> (mpsc::Sender<Flushed>, thread::JoinHandle<()>) {
+        let (sender, receiver) = mpsc::channel::<Flushed>();
 
-            if let Some(time_frame) = registry.finalize() {
-                for mut backend in backends {
-                    log::trace!("Notifying backend {:?}", backend.0);
-                    backend.1.publish(&now, &time_frame);
+        let handle = thread::spawn(move || {
+            let options = config.backend_options();
+            let mut publishers: Vec<Option<backend::Publisher>> =
+                config.backends.enabled.iter().map(|_| None).collect();
+            let mut failures = vec![0; publishers.len()];
+            let mut flush_duration = None;
+            let budget = ThreadBudget::new(config.max_threads);
+
+            for (time, mut registry) in receiver {
+                let time = if config.align_timestamps {
+                    align_timestamp(time, config.refresh_interval)
+                } else {
+                    time
+                };
+
+                for (((name, backend_config), publisher), failures) in config
+                    .backends
+                    .enabled
+                    .iter()
+                    .zip(publishers.iter_mut())
+                    .zip(failures.iter_mut())
+                {
+                    if publisher.is_none() {
+                        *publisher = construct_publisher(name, backend_config, &options, failures);
+                    }
+                }
+
+                log::info!("Aggregating collected metrics");
+
+                let started = Instant::now();
+
+                add_internal_metrics(&mut registry, &config, flush_duration);
+
+                let mut time_frame = registry.finalize();
+
+                time_frame.derived = derived::evaluate(&config.derived, |reference| {
+                    backend::value(&time_frame, &options, reference)
+                });
+
+                let summary = time_frame.summary();
+
+                let outcomes = backend::publish_all(
+                    &publishers.iter().flatten().cloned().collect::<Vec<_>>(),
+                    &time,
+                    Arc::new(time_frame),
+                    config.skew_warning_threshold,
+                    &budget,
+                );
+
+                let elapsed = started.elapsed();
+
+                flush_duration = Some(elapsed);
+
+                log::info!(
+                    "Flushed {} counters, {} timings and {} gauges from {} observations in {:?}",
+                    summary.counters,
+                    summary.timings,
+                    summary.gauges,
+                    summary.observations,
+                    elapsed
+                );
+
+                for (name, outcome) in outcomes {
+                    // Timed out backend may be stuck for good, so it's never reused
+                    let is_unhealthy = |backend: &backend::Shared| match &outcome {
+                        backend::Outcome::TimedOut => true,
+                        backend::Outcome::Failed(_) => backend
+                            .try_lock()
+                            .map_or(true, |backend| !backend.is_healthy()),
+                        _ => false,
+                    };
+
+                    publishers
+                        .iter_mut()
+                        .filter(|publisher| {
+                            publisher.as_ref().is_some_and(|(publisher_name, backend, _)| {
+                                *publisher_name == name && is_unhealthy(backend)
+                            })
+                        })
+                        .for_each(|publisher| {
+                            log::warn!("Backend {name} is unhealthy, it will be built again in the next cycle");
+
+                            *publisher = None
+                        });
                 }
             }
         });
 
+        (sender, handle)
+    }
+
+    fn flush(
+        mut registry: Registry,
+        publisher: &mpsc::Sender<Flushed>,
+        self_metrics: &mut SelfMetrics,
+    ) -> Registry {
+        self_metrics.drain_into(&mut registry);
+
+        let new_registry = registry.new_with_carryover();
+
+        if publisher.send((Utc::now(), registry)).is_err() {
+            log::error!("Publisher thread is not running, dropping collected metrics");
+        }
+
         new_registry
     }
 
-    let mut registry = Registry::default();
+    fn ingest(
+        metrics: Vec<Metric>,
+        rewriter: &Rewriter,
+        name_filter: &NameFilter,
+        registry: &mut Registry,
+        cycle: &mut Cycle,
+        publisher: &mpsc::Sender<Flushed>,
+        self_metrics: &mut SelfMetrics,
+    ) {
+        for mut metric in metrics {
+            rewriter.rewrite(&mut metric);
+
+            if !name_filter.is_allowed(&metric.name) {
+                log::trace!("Dropping filtered metric: {}", &metric.name);
+
+                continue;
+            }
+
+            log::trace!("Parsed metric: {:?}", &metric);
+
+            match registry.add(&metric) {
+                Added::Overflow => {
+                    log::warn!("Overflow detected for metric: {}", &metric.name);
+
+                    self_metrics.overflow_flushes += 1;
+                }
+                Added::Aggregated if cycle.record_added() => {
+                    log::debug!(
+                        "Aggregated {} metrics in this cycle, flushing early",
+                        cycle.added
+                    );
+                }
+                _ => continue,
+            }
+
+            if cycle.end() {
+                *registry = flush(std::mem::take(registry), publisher, self_metrics);
+                cycle.restart();
+            }
+        }
+    }
+
+    let (publisher, publisher_handle) = start_publisher(config.clone());
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        signal_hook::flag::register(signal, shutdown.clone())?;
+    }
+    let snapshot = config.gauge_snapshot_path.clone().map(GaugeSnapshot::new);
+    let save_snapshot = |registry: &Registry| {
+        if let Some(snapshot) = &snapshot {
+            if let Err(err) = snapshot.save(registry.gauges()) {
+                log::error!("Unable to save gauge snapshot: {err}");
+            }
+        }
+    };
+    let mut registry = match &snapshot {
+        Some(snapshot) => Registry::with_gauges(config.registry_options(), snapshot.load()),
+        None => Registry::new(config.registry_options()),
+    };
+    let mut self_metrics = SelfMetrics::new(config.self_metrics_prefix.clone());
+    let options = config.protocol_options();
+    let rewriter = config.rewriter()?;
+    let name_filter = config.name_filter()?;
+    let mut deduplicator = config
+        .dedup
+        .as_ref()
+        .map(|dedup| Deduplicator::new(dedup.window, dedup.capacity));
+    let spool = config.spool.as_ref().map(|spool| {
+        (
+            Spool::new(spool.dir.clone(), spool.archive_dir.clone()),
+            spool.poll_interval,
+        )
+    });
+    let mut spool_polled = Instant::now();
+    let mut buff = vec![0; config.receive_buffer_size + 1];
+
+    while !shutdown.load(Ordering::Relaxed) && !cycle.ended {
+        let elapsed = cycle.elapsed();
 
-    loop {
-        let elapsed = now.elapsed();
+        let over_memory_cap = registry.needs_flush();
 
-        if elapsed > config.refresh_interval {
-            registry = flush(registry, config.clone());
-            now = Instant::now();
+        if over_memory_cap {
+            log::warn!("Estimated memory reached max-memory, flushing early");
+        }
+
+        if elapsed > config.refresh_interval || over_memory_cap {
+            if !cycle.end() {
+                break;
+            }
+
+            registry = flush(registry, &publisher, &mut self_metrics);
+            cycle.restart();
+
+            save_snapshot(&registry);
         } else {
-            socket
-                .set_read_timeout(Some(config.refresh_interval - elapsed))
-                .expect("Why are we unable to set read timeout?");
+            let mut timeout = (config.refresh_interval - elapsed).min(SHUTDOWN_CHECK_INTERVAL);
+
+            if let Some((_, poll_interval)) = &spool {
+                timeout = timeout.min(*poll_interval);
+            }
+
+            // Timeout of zero is rejected
+            let timeout = timeout.max(Duration::from_millis(1));
+
+            if let Err(err) = socket.set_read_timeout(Some(timeout)) {
+                log::error!(
+                    "Unable to set read timeout to {timeout:?}, keeping the previous one: {err}"
+                );
+            }
         }
 
-        let mut buff = [0; 2048];
+        if let Some((spool, poll_interval)) = &spool {
+            if spool_polled.elapsed() >= *poll_interval {
+                spool_polled = Instant::now();
+
+                match spool.collect() {
+                    Ok(payloads) => {
+                        for payload in payloads {
+                            let metrics = parsed_metrics(
+                                protocol::parse_protocol_with(&payload, &options),
+                                &mut self_metrics,
+                            );
+
+                            ingest(
+                                metrics,
+                                &rewriter,
+                                &name_filter,
+                                &mut registry,
+                                &mut cycle,
+                                &publisher,
+                                &mut self_metrics,
+                            );
+                        }
+                    }
+                    Err(err) => log::error!("Unable to read spool directory: {err}"),
+                }
+            }
+        }
 
         log::info!("Waiting for data from socket");
 
-        match socket.recv(&mut buff) {
-            Ok(_) => {
-                let payload = match std::str::from_utf8(&buff) {
-                    Ok(payload) => payload,
-                    Err(_) => {
-                        log::warn!("Invalid payload received of size: {}", buff.len());
-                        log::trace!("Payload: {:?}", &buff);
+        match receive(&socket, &mut buff) {
+            Ok((payload, truncated)) => {
+                self_metrics.datagrams_received += 1;
 
-                        continue;
-                    }
-                };
+                if truncated {
+                    log::warn!(
+                        "Datagram larger than receive-buffer-size of {} bytes was truncated, metrics past it are dropped",
+                        config.receive_buffer_size
+                    );
+                }
 
-                for metric in protocol::parse_protocol(payload) {
-                    log::trace!("Parsed metric: {:?}", &metric);
+                if let Some(deduplicator) = &mut deduplicator {
+                    if deduplicator.is_duplicate(payload, Instant::now()) {
+                        log::trace!("Dropping duplicate payload");
 
-                    if !registry.add(&metric) {
-                        log::warn!("Overflow detected for metric: {}", &metric.name);
+                        self_metrics.dedup_dropped += 1;
 
-                        registry = flush(registry, config.clone());
-                        now = Instant::now();
+                        continue;
                     }
                 }
+
+                let metrics = if payload.first() == Some(&protocol::BINARY_MAGIC) {
+                    parsed_metrics(
+                        protocol::parse_binary_with(payload, &options),
+                        &mut self_metrics,
+                    )
+                } else {
+                    match std::str::from_utf8(payload) {
+                        Ok(payload) => parsed_metrics(
+                            protocol::parse_protocol_with(payload, &options),
+                            &mut self_metrics,
+                        ),
+                        Err(_) => {
+                            log::warn!("Invalid payload received of size: {}", payload.len());
+                            log::trace!("Payload: {:?}", payload);
+
+                            continue;
+                        }
+                    }
+                };
+
+                ingest(
+                    metrics,
+                    &rewriter,
+                    &name_filter,
+                    &mut registry,
+                    &mut cycle,
+                    &publisher,
+                    &mut self_metrics,
+                );
             }
             Err(err) => {
-                if err.kind() != ErrorKind::WouldBlock {
+                if !matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) {
                     log::error!("Error occurred: {}", err);
 
                     exit(1);
@@ -234,4 +1507,568 @@ fn main() -> Result<(), Box<dyn Error>> {
             }
         }
     }
+
+    log::info!("Shutting down, publishing remaining metrics");
+
+    self_metrics.drain_into(&mut registry);
+
+    save_snapshot(&registry);
+
+    // Everything collected is published, including samples that would otherwise be carried over
+    if publisher.send((Utc::now(), registry)).is_err() {
+        log::error!("Publisher thread is not running, dropping collected metrics");
+    }
+
+    drop(publisher);
+
+    if publisher_handle.join().is_err() {
+        log::error!("Publisher thread panicked");
+
+        exit(1);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONFIG: &str = r#"
+host = "127.0.0.1"
+port = 3232
+refresh-interval = '60 s'
+
+[backend]
+enabled = ['console', 'postgresql']
+
+[backend.available.console]
+type = "console"
+
+[backend.available.postgresql]
+type = "postgresql"
+host = "127.0.0.1"
+port = 5432
+user = "pgsql"
+password = ""
+db-name = "metco"
+
+[profiles.dev]
+enabled = ['console']
+
+[profiles.prod]
+enabled = ['postgresql']
+"#;
+
+    fn enabled(config: &Config) -> Vec<&str> {
+        config
+            .backends
+            .enabled
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn environment_variables_are_interpolated_in_backends() {
+        let lookup = |name: &str| (name == "PG_PASSWORD").then(|| "secret".to_string());
+
+        assert_eq!(
+            Ok("secret".to_string()),
+            interpolate("${PG_PASSWORD}", &lookup)
+        );
+        assert_eq!(
+            Ok("user:secret@secret.host".to_string()),
+            interpolate("user:${PG_PASSWORD}@${PG_PASSWORD}.host", &lookup)
+        );
+        assert_eq!(Ok("plain".to_string()), interpolate("plain", &lookup));
+        assert_eq!(
+            Err("Environment variable PG_USER is not set".to_string()),
+            interpolate("${PG_USER}", &lookup)
+        );
+        assert!(interpolate("${PG_PASSWORD", &lookup).is_err());
+
+        std::env::set_var("INTERPOLATION_TEST_PG_PASSWORD_SET", "secret");
+
+        let config = load_config(
+            Figment::from(Toml::string(CONFIG)).merge(Serialized::default(
+                "backend.available.postgresql.password",
+                "${INTERPOLATION_TEST_PG_PASSWORD_SET}",
+            )),
+            None,
+        )
+        .unwrap();
+
+        let Backend::PostgreSQL { password, .. } = &config.backends.enabled[1].1.backend else {
+            panic!("Expected PostgreSQL backend");
+        };
+
+        assert_eq!("secret", password);
+
+        let err = load_config(
+            Figment::from(Toml::string(CONFIG)).merge(Serialized::default(
+                "backend.available.postgresql.password",
+                "${INTERPOLATION_TEST_PG_PASSWORD_UNSET}",
+            )),
+            None,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            "Backend postgresql is not valid. Environment variable INTERPOLATION_TEST_PG_PASSWORD_UNSET is not set.",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn profile_overrides_enabled_backends() {
+        let config = load_config(Figment::from(Toml::string(CONFIG)), None).unwrap();
+        assert_eq!(vec!["console", "postgresql"], enabled(&config));
+
+        let config =
+            load_config(Figment::from(Toml::string(CONFIG)), Some("dev".to_string())).unwrap();
+        assert_eq!(vec!["console"], enabled(&config));
+
+        let config = load_config(
+            Figment::from(Toml::string(CONFIG)).merge(Serialized::default("profile", "prod")),
+            None,
+        )
+        .unwrap();
+        assert_eq!(vec!["postgresql"], enabled(&config));
+    }
+
+    #[test]
+    fn unknown_profile_is_an_error() {
+        assert!(load_config(
+            Figment::from(Toml::string(CONFIG)),
+            Some("staging".to_string())
+        )
+        .unwrap_err()
+        .to_string()
+        .contains("Profile staging is not defined"));
+    }
+
+    #[test]
+    fn enabling_more_than_max_backends_is_an_error() {
+        let figment = Figment::from(Toml::string(CONFIG));
+
+        assert!(load_config(
+            figment
+                .clone()
+                .merge(Serialized::default("backend.max-backends", 2)),
+            None
+        )
+        .is_ok());
+
+        assert!(load_config(
+            figment.merge(Serialized::default("backend.max-backends", 1)),
+            None
+        )
+        .unwrap_err()
+        .to_string()
+        .contains("There are 2 backends listed in enabled, but at most 1 are allowed."));
+    }
+
+    #[test]
+    fn unreadable_ca_certificate_is_an_error() {
+        let missing =
+            std::env::temp_dir().join(format!("metco-missing-ca-{}.pem", std::process::id()));
+
+        assert!(tls_connector(None).is_ok());
+        assert!(tls_connector(Some(&missing))
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("Unable to read CA certificate"));
+
+        let invalid =
+            std::env::temp_dir().join(format!("metco-invalid-ca-{}.pem", std::process::id()));
+
+        fs::write(&invalid, "not a certificate").unwrap();
+
+        assert!(tls_connector(Some(&invalid))
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("Invalid CA certificate"));
+    }
+
+    #[test]
+    fn enabling_same_backend_twice_is_an_error() {
+        assert!(load_config(
+            Figment::from(Toml::string(CONFIG)).merge(Serialized::default(
+                "backend.enabled",
+                vec!["console", "postgresql", "console"]
+            )),
+            None
+        )
+        .unwrap_err()
+        .to_string()
+        .contains("Backend console is listed multiple times in enabled."));
+    }
+
+    #[test]
+    fn check_fails_when_enabled_backend_can_not_be_constructed() {
+        let config =
+            load_config(Figment::from(Toml::string(CONFIG)), Some("dev".to_string())).unwrap();
+
+        assert_eq!(Ok(()), check(&config));
+
+        let missing =
+            std::env::temp_dir().join(format!("metco-check-ca-{}.pem", std::process::id()));
+
+        let config = load_config(
+            Figment::from(Toml::string(CONFIG))
+                .merge(Serialized::default(
+                    "backend.available.postgresql.tls",
+                    "require",
+                ))
+                .merge(Serialized::default(
+                    "backend.available.postgresql.ca-cert",
+                    missing,
+                )),
+            Some("prod".to_string()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            Err("Unable to construct backends: postgresql".to_string()),
+            check(&config)
+        );
+    }
+
+    #[test]
+    fn failed_backend_construction_is_counted_until_it_succeeds() {
+        let missing =
+            std::env::temp_dir().join(format!("metco-construct-ca-{}.pem", std::process::id()));
+
+        let config = load_config(
+            Figment::from(Toml::string(CONFIG))
+                .merge(Serialized::default(
+                    "backend.available.postgresql.tls",
+                    "require",
+                ))
+                .merge(Serialized::default(
+                    "backend.available.postgresql.ca-cert",
+                    missing,
+                )),
+            None,
+        )
+        .unwrap();
+        let options = config.backend_options();
+
+        let (name, postgresql) = &config.backends.enabled[1];
+        let mut failures = 0;
+
+        assert!(construct_publisher(name, postgresql, &options, &mut failures).is_none());
+        assert!(construct_publisher(name, postgresql, &options, &mut failures).is_none());
+        assert_eq!(2, failures);
+
+        let (name, console) = &config.backends.enabled[0];
+
+        assert!(construct_publisher(name, console, &options, &mut failures).is_some());
+        assert_eq!(0, failures);
+    }
+
+    #[test]
+    fn binding_port_in_use_is_an_error() {
+        let bound = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = bound.local_addr().unwrap().port();
+
+        let err = bind("127.0.0.1", port).unwrap_err();
+
+        assert_eq!(ErrorKind::AddrInUse, err.kind());
+
+        let err = err.to_string();
+
+        assert!(err.starts_with(&format!("Unable to bind to 127.0.0.1:{port}: ")));
+        assert!(err.ends_with("is another instance already running?"));
+    }
+
+    #[test]
+    fn bound_socket_has_read_timeout() {
+        let socket = bind("127.0.0.1", 0).unwrap();
+
+        assert_eq!(
+            Some(SHUTDOWN_CHECK_INTERVAL),
+            socket.read_timeout().unwrap()
+        );
+    }
+
+    #[test]
+    fn only_received_bytes_are_parsed() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        sender
+            .send_to(b"abc|c|12", receiver.local_addr().unwrap())
+            .unwrap();
+
+        let mut buff = [0; 2048];
+        let (payload, truncated) = receive(&receiver, &mut buff).unwrap();
+
+        assert!(!truncated);
+        assert_eq!(
+            vec![Metric {
+                name: "abc".to_string(),
+                kind: MetricKind::Counter(12.),
+            }],
+            protocol::parse_protocol(std::str::from_utf8(payload).unwrap())
+        );
+    }
+
+    #[test]
+    fn reused_buffer_does_not_leak_previous_datagram() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        for payload in [&b"abcdef|c|12\nghi|c|3"[..], &b"abc|c|1"[..]] {
+            sender
+                .send_to(payload, receiver.local_addr().unwrap())
+                .unwrap();
+        }
+
+        let mut buff = vec![0; 2049];
+
+        receive(&receiver, &mut buff).unwrap();
+
+        let (payload, truncated) = receive(&receiver, &mut buff).unwrap();
+
+        assert!(!truncated);
+        assert_eq!(b"abc|c|1", payload);
+        assert_eq!(
+            vec![Metric {
+                name: "abc".to_string(),
+                kind: MetricKind::Counter(1.),
+            }],
+            protocol::parse_protocol(std::str::from_utf8(payload).unwrap())
+        );
+    }
+
+    #[test]
+    fn oversized_datagram_is_truncated_at_last_complete_line() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let fitting = b"abc|c|1\ndef|c|2\n";
+        let oversized = b"abc|c|1\ndef|c|2\nghi|c|12345";
+
+        for payload in [&fitting[..], &oversized[..]] {
+            sender
+                .send_to(payload, receiver.local_addr().unwrap())
+                .unwrap();
+        }
+
+        let mut buff = [0; 17];
+
+        let (payload, truncated) = receive(&receiver, &mut buff).unwrap();
+
+        assert!(!truncated);
+        assert_eq!(&fitting[..], payload);
+
+        let (payload, truncated) = receive(&receiver, &mut buff).unwrap();
+
+        assert!(truncated);
+        assert_eq!(b"abc|c|1\ndef|c|2", payload);
+    }
+
+    #[test]
+    fn refresh_interval_is_published_as_gauge() {
+        for (interval, expected) in [("90 s", 90.), ("1500ms", 1.5)] {
+            let config = load_config(
+                Figment::from(Toml::string(CONFIG))
+                    .merge(Serialized::default("refresh-interval", interval)),
+                None,
+            )
+            .unwrap();
+
+            let mut registry = Registry::new(config.registry_options());
+            add_internal_metrics(&mut registry, &config, None);
+
+            assert_eq!(
+                expected,
+                registry.finalize().gauges["metco.refresh_interval_seconds"]
+            );
+        }
+    }
+
+    #[test]
+    fn self_metrics_are_published_under_configured_prefix() {
+        let config = load_config(
+            Figment::from(Toml::string(CONFIG))
+                .merge(Serialized::default("self-metrics-prefix", "daemon")),
+            None,
+        )
+        .unwrap();
+
+        let mut registry = Registry::new(config.registry_options());
+        let mut self_metrics = SelfMetrics::new(config.self_metrics_prefix.clone());
+
+        self_metrics.datagrams_received += 2;
+        self_metrics.dedup_dropped += 1;
+
+        let metrics = parsed_metrics(
+            protocol::parse_protocol_with("abc|c|1\ngarbage\n|c|1", &config.protocol_options()),
+            &mut self_metrics,
+        );
+
+        for metric in metrics {
+            assert_eq!(Added::Aggregated, registry.add(&metric));
+        }
+
+        self_metrics.drain_into(&mut registry);
+        add_internal_metrics(&mut registry, &config, Some(Duration::from_millis(250)));
+
+        let time_frame = registry.finalize();
+
+        assert_eq!(
+            2.,
+            time_frame.counters["daemon.internal.datagrams_received"].sum()
+        );
+        assert_eq!(
+            1.,
+            time_frame.counters["daemon.internal.malformed_lines"].sum()
+        );
+        assert_eq!(
+            0.,
+            time_frame.counters["daemon.internal.overflow_flushes"].sum()
+        );
+        assert_eq!(1., time_frame.counters["daemon.empty_names_dropped"].sum());
+        assert_eq!(1., time_frame.counters["daemon.dedup_dropped"].sum());
+        assert_eq!(
+            0.25,
+            time_frame.gauges["daemon.internal.flush_duration_seconds"]
+        );
+        assert_eq!(0, self_metrics.datagrams_received);
+        assert_eq!(0, self_metrics.dedup_dropped);
+    }
+
+    #[test]
+    fn cycle_is_flushed_once_enough_metrics_are_aggregated() {
+        let mut cycle = Cycle::new(Some(3));
+
+        assert!(!cycle.record_added());
+        assert!(!cycle.record_added());
+        assert!(cycle.record_added());
+
+        cycle.restart();
+
+        assert!(!cycle.record_added());
+
+        let mut unlimited = Cycle::new(None);
+
+        assert!((0..1000).all(|_| !unlimited.record_added()));
+    }
+
+    #[test]
+    fn cycle_ends_the_run_once_flushed_with_once() {
+        let mut cycle = Cycle::new(Some(1));
+
+        assert!(cycle.record_added());
+        assert!(cycle.end());
+        assert!(!cycle.ended);
+
+        let mut once = Cycle {
+            once: true,
+            ..Cycle::new(Some(1))
+        };
+
+        assert!(once.record_added());
+        assert!(!once.end());
+        assert!(once.ended);
+    }
+
+    #[test]
+    fn timestamps_are_aligned_down_to_interval() {
+        let time = |value: &str| value.parse::<DateTime<Utc>>().unwrap();
+
+        let cases = [
+            ("2024-05-01T12:01:04.250Z", 60, "2024-05-01T12:01:00Z"),
+            ("2024-05-01T12:01:00Z", 60, "2024-05-01T12:01:00Z"),
+            ("2024-05-01T12:01:04.250Z", 10, "2024-05-01T12:01:00Z"),
+            ("2024-05-01T12:01:14.999Z", 10, "2024-05-01T12:01:10Z"),
+            ("2024-05-01T12:01:04.250Z", 1, "2024-05-01T12:01:04Z"),
+            ("2024-05-01T12:14:59Z", 300, "2024-05-01T12:10:00Z"),
+            ("2024-05-01T12:59:59Z", 3600, "2024-05-01T12:00:00Z"),
+        ];
+
+        for (value, interval, expected) in cases {
+            assert_eq!(
+                time(expected),
+                align_timestamp(time(value), Duration::from_secs(interval)),
+                "{value} aligned to {interval}s"
+            );
+        }
+
+        assert_eq!(
+            time("2024-05-01T12:01:04.250Z"),
+            align_timestamp(time("2024-05-01T12:01:04.250Z"), Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn percentiles_must_be_within_unit_range() {
+        let config = load_config(
+            Figment::from(Toml::string(CONFIG))
+                .merge(Serialized::default("percentiles", vec![0.5, 0.95, 0.999])),
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec!["p50", "p95", "p99_9"],
+            config
+                .backend_options()
+                .percentiles
+                .iter()
+                .map(Percentile::label)
+                .collect::<Vec<_>>()
+        );
+
+        let error = load_config(
+            Figment::from(Toml::string(CONFIG))
+                .merge(Serialized::default("percentiles", vec![0.5, 95.])),
+            None,
+        )
+        .unwrap_err();
+
+        assert!(error.to_string().contains("Percentile 95 is out of range"));
+    }
+
+    #[test]
+    fn histogram_buckets_must_be_increasing() {
+        let error = load_config(
+            Figment::from(Toml::string(CONFIG))
+                .merge(Serialized::default("histogram-buckets", vec![0.1, 1., 1.])),
+            None,
+        )
+        .unwrap_err();
+
+        assert!(error
+            .to_string()
+            .contains("Histogram buckets must be increasing, but 1 is followed by 1"));
+    }
+
+    #[test]
+    fn refresh_interval_must_not_be_zero() {
+        let error = load_config(
+            Figment::from(Toml::string(CONFIG))
+                .merge(Serialized::default("refresh-interval", "0s")),
+            None,
+        )
+        .unwrap_err();
+
+        assert!(error
+            .to_string()
+            .contains("Refresh interval must be greater than zero"));
+
+        let config = load_config(
+            Figment::from(Toml::string(CONFIG))
+                .merge(Serialized::default("refresh-interval", "50ms")),
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(Duration::from_millis(50), config.refresh_interval);
+    }
 }
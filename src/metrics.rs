@@ -1,4 +1,10 @@
-use std::collections::HashMap;
+use rand::Rng;
+use serde::Deserialize;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TimerResolution {
@@ -8,18 +14,55 @@ pub enum TimerResolution {
     NanoSeconds,
 }
 
+impl TimerResolution {
+    /// Number of nanoseconds in a single unit of the resolution.
+    pub fn nanoseconds(&self) -> f64 {
+        match self {
+            TimerResolution::Seconds => 1_000_000_000.,
+            TimerResolution::MilliSeconds => 1_000_000.,
+            TimerResolution::MicroSeconds => 1_000.,
+            TimerResolution::NanoSeconds => 1.,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum GaugeOperation {
-    Set(i64),
-    Modify(i64),
+    Set(f64),
+    Modify(f64),
+    /// Modifies the gauge by the first value like [`GaugeOperation::Modify`], but never below
+    /// the second one.
+    ModifyClamped(f64, f64),
     Remove,
+    /// Sets the gauge like [`GaugeOperation::Set`] while also collecting the value as a sample
+    /// for per-cycle statistics. Value is never negative.
+    Summary(f64),
 }
 
 #[derive(Debug, PartialEq)]
 pub enum MetricKind {
-    Counter(u64),
-    Timing(u64, TimerResolution),
+    Counter(f64),
+    /// Value, resolution and weight, which is number of observations the value stands for.
+    Timing(u64, TimerResolution, u64),
     Gauge(GaugeOperation),
+    /// Value counted once per cycle no matter how many times it's observed.
+    Set(String),
+    /// Ever-increasing total, like ones in `/proc`, which is counted as the increase since the
+    /// previously reported total.
+    Absolute(f64),
+    /// Observation counted in a bucket of the histogram, so memory does not grow with samples.
+    Histogram(f64),
+}
+
+/// What became of a metric added to the registry.
+#[derive(Debug, PartialEq)]
+pub enum Added {
+    Aggregated,
+    /// Metric was dropped, either because its value is not finite or because the registry is at
+    /// its memory or cardinality cap. Nothing is gained by flushing early.
+    Rejected,
+    /// Gauge would no longer be finite, so the registry should be flushed before adding again.
+    Overflow,
 }
 
 #[derive(Debug, PartialEq)]
@@ -28,162 +71,935 @@ pub struct Metric {
     pub kind: MetricKind,
 }
 
+/// Percentile within `[0, 1]`, like `0.95` for p95.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "f64")]
+pub struct Percentile(f64);
+
+impl TryFrom<f64> for Percentile {
+    type Error = String;
+
+    fn try_from(value: f64) -> Result<Self, Self::Error> {
+        if !(0. ..=1.).contains(&value) {
+            return Err(format!(
+                "Percentile {value} is out of range, it must be within [0, 1]. Use 0.95 instead of 95 for p95."
+            ));
+        }
+
+        Ok(Self(value))
+    }
+}
+
+impl Percentile {
+    /// Name of the percentile without dots, like `p95` for `0.95` or `p99_9` for `0.999`, so it
+    /// can be a field of a dotted name.
+    pub fn label(&self) -> String {
+        format!(
+            "p{}",
+            format!("{:.6}", self.0 * 100.)
+                .trim_end_matches('0')
+                .trim_end_matches('.')
+                .replace('.', "_")
+        )
+    }
+}
+
+/// Statistics over weighted samples, where a sample `(value, weight)` is treated as `weight`
+/// observations of `value`. Percentiles are taken from sorted observations as if every sample
+/// was repeated `weight` times. Statistics without samples, like those of known metrics, only have
+/// count and sum, both zero, while other statistics are `None`.
+///
+/// Samples can also be a random sample of observations, in which case count, sum, min and max
+/// are still exact, while median, percentiles and standard deviation are estimated from them.
 #[derive(Debug)]
 pub struct Statistics {
-    list: Vec<u64>,
-    sum: u64,
-    std: f64,
+    list: Vec<(f64, u64)>,
+    count: u64,
+    sum: f64,
+    /// Total weight of samples, less than count when they are a random sample of observations.
+    weight: u64,
+    min: f64,
+    max: f64,
+    /// Computed on first use, since it takes another pass over the list.
+    std: OnceLock<f64>,
 }
 
 impl Statistics {
-    fn new(mut list: Vec<u64>) -> Result<Self, ()> {
-        assert!(!list.is_empty());
+    /// Sum and count saturate instead of overflowing, so a series is never dropped because of
+    /// its aggregate. Values are sorted in total order, though registry never collects NaN.
+    fn new(mut list: Vec<(f64, u64)>) -> Self {
+        list.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+
+        let mut sum = 0f64;
+        let mut count = 0u64;
 
-        list.sort();
+        for (value, weight) in &list {
+            sum += value * *weight as f64;
+            count = count.saturating_add(*weight);
+        }
 
-        let mut sum = 0u64;
+        // Samples are never negative, so the sum can only overflow to positive infinity
+        let sum = sum.min(f64::MAX);
 
-        for item in &list {
-            match sum.checked_add(*item) {
-                Some(val) => sum = val,
-                None => return Err(()),
-            }
+        Self {
+            min: list.first().map_or(f64::NAN, |(value, _)| *value),
+            max: list.last().map_or(f64::NAN, |(value, _)| *value),
+            weight: count,
+            list,
+            count,
+            sum,
+            std: OnceLock::new(),
         }
+    }
 
-        let avg = sum as f64 / list.len() as f64;
-        let std = list
+    /// Statistics over a random sample of observations described by `totals`.
+    fn sampled(list: Vec<(f64, u64)>, totals: Totals) -> Self {
+        Self {
+            count: totals.count,
+            sum: totals.sum.min(f64::MAX),
+            min: totals.min,
+            max: totals.max,
+            ..Self::new(list)
+        }
+    }
+
+    fn value_at(&self, rank: u64) -> f64 {
+        let mut seen = 0;
+
+        self.list
             .iter()
-            .fold(0., |acc, item| acc + (*item as f64 - avg).powf(2.))
-            .powf(0.5);
+            .find(|(_, weight)| {
+                seen += weight;
 
-        Ok(Self { list, sum, std })
+                rank < seen
+            })
+            .expect("Rank must be less than count")
+            .0
     }
 
-    pub fn sum(&self) -> u64 {
+    pub fn sum(&self) -> f64 {
         self.sum
     }
 
-    pub fn count(&self) -> usize {
-        self.list.len()
+    pub fn count(&self) -> u64 {
+        self.count
     }
 
-    pub fn average(&self) -> f64 {
-        self.sum as f64 / self.list.len() as f64
+    fn is_empty(&self) -> bool {
+        self.weight == 0
     }
 
-    pub fn median(&self) -> f64 {
-        let len = self.list.len();
+    pub fn average(&self) -> Option<f64> {
+        (!self.is_empty()).then(|| self.sum / self.count as f64)
+    }
 
-        if len & 1 == 0 {
-            (self.list[len / 2 - 1] as f64 + self.list[len / 2] as f64) / 2.
-        } else {
-            self.list[len / 2] as f64
+    pub fn median(&self) -> Option<f64> {
+        let count = self.weight;
+
+        match count {
+            0 => None,
+            _ if count & 1 == 0 => {
+                Some((self.value_at(count / 2 - 1) + self.value_at(count / 2)) / 2.)
+            }
+            _ => Some(self.value_at(count / 2)),
         }
     }
 
-    pub fn std(&self) -> f64 {
-        self.std
+    pub fn std(&self) -> Option<f64> {
+        let avg = self.average()?;
+
+        Some(*self.std.get_or_init(|| {
+            // Deviation of a random sample is scaled up to the observations it stands for
+            let scale = self.count as f64 / self.weight as f64;
+
+            (scale
+                * self.list.iter().fold(0., |acc, (value, weight)| {
+                    acc + *weight as f64 * (*value - avg).powf(2.)
+                }))
+            .powf(0.5)
+        }))
     }
 
-    pub fn percentile(&self, p: f64) -> u64 {
-        self.list
-            [((self.list.len() as f64 * p.max(0.).min(1.)).floor() as usize).min(self.list.len())]
+    pub fn min(&self) -> Option<f64> {
+        (!self.is_empty()).then_some(self.min)
+    }
+
+    pub fn max(&self) -> Option<f64> {
+        (!self.is_empty()).then_some(self.max)
+    }
+
+    pub fn percentile(&self, p: Percentile) -> Option<f64> {
+        (!self.is_empty()).then(|| {
+            self.value_at(((self.weight as f64 * p.0).floor() as u64).min(self.weight - 1))
+        })
+    }
+}
+
+/// Observations counted per bucket, where a bucket holds values up to and including its upper
+/// bound, and the last bucket holds values above the highest bound.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: Vec<f64>) -> Self {
+        Self {
+            counts: vec![0; bounds.len() + 1],
+            bounds,
+            sum: 0.,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Sum saturates like the one of [`Statistics`].
+    pub fn sum(&self) -> f64 {
+        self.sum.min(f64::MAX)
+    }
+
+    /// Upper bounds with cumulative counts of observations up to them, ending with `+Inf` which
+    /// counts every observation.
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        self.bounds
+            .iter()
+            .copied()
+            .chain([f64::INFINITY])
+            .zip(self.counts.iter().scan(0, |total, count| {
+                *total += count;
+
+                Some(*total)
+            }))
+            .collect()
     }
 }
 
 #[derive(Debug)]
 pub struct TimeFrame {
     pub counters: HashMap<String, Statistics>,
-    pub gauges: HashMap<String, i64>,
+    pub gauges: HashMap<String, f64>,
     pub timings: HashMap<String, Statistics>,
+    /// Statistics over values of summary gauges set within the cycle.
+    pub summaries: HashMap<String, Statistics>,
+    /// Running totals of counters, carried across cycles. Only kept with `cumulative_counters`.
+    pub cumulative_counters: HashMap<String, f64>,
+    /// Number of distinct values observed per set within the cycle.
+    pub sets: HashMap<String, u64>,
+    pub histograms: HashMap<String, Histogram>,
+    /// Values of derived metrics, evaluated once the time frame is finalized.
+    pub derived: HashMap<String, f64>,
 }
 
-impl TryFrom<Registry> for TimeFrame {
-    type Error = ();
+/// Size of a time frame, logged once it's published.
+#[derive(Debug, PartialEq)]
+pub struct Summary {
+    pub counters: usize,
+    pub timings: usize,
+    pub gauges: usize,
+    /// Observations aggregated into counters, timings, summaries and histograms.
+    pub observations: u64,
+}
 
-    fn try_from(value: Registry) -> Result<Self, Self::Error> {
-        Ok(TimeFrame {
-            gauges: value.gauges,
-            counters: value.counters.into_iter().fold(
-                HashMap::default(),
-                |mut map, (name, list)| {
-                    if let Ok(statistics) = Statistics::new(list) {
-                        map.insert(name, statistics);
-                    }
+impl TimeFrame {
+    pub fn summary(&self) -> Summary {
+        let observations = self
+            .counters
+            .values()
+            .chain(self.timings.values())
+            .chain(self.summaries.values())
+            .map(Statistics::count)
+            .chain(self.histograms.values().map(Histogram::count))
+            .fold(0u64, u64::saturating_add);
+
+        Summary {
+            counters: self.counters.len(),
+            timings: self.timings.len(),
+            gauges: self.gauges.len(),
+            observations,
+        }
+    }
+}
+
+impl From<Registry> for TimeFrame {
+    fn from(mut value: Registry) -> Self {
+        if value.shed > 0 {
+            value.counters.insert(
+                format!("{}.memory_shed", value.options.self_metrics_prefix),
+                Samples::Raw(vec![value.shed as f64]),
+            );
+        }
+
+        if value.rejected > 0 {
+            value.counters.insert(
+                format!("{}.cardinality_rejected", value.options.self_metrics_prefix),
+                Samples::Raw(vec![value.rejected as f64]),
+            );
+        }
+
+        // Samples of other cycles are carried over and published later
+        if value.is_statistics_cycle() {
+            for name in &value.options.known_counters {
+                value.counters.entry(name.clone()).or_default();
+            }
+
+            for name in &value.options.known_timings {
+                value.timings.entry(name.clone()).or_default();
+            }
+        }
 
-                    map
-                },
-            ),
+        TimeFrame {
+            derived: HashMap::default(),
+            sets: value
+                .sets
+                .into_iter()
+                .map(|(name, values)| (name, values.len() as u64))
+                .collect(),
+            gauges: value.gauges,
+            histograms: value.histograms,
+            cumulative_counters: value
+                .cumulative_counters
+                .into_iter()
+                .map(|(name, (total, _))| (name, total))
+                .collect(),
+            counters: value
+                .counters
+                .into_iter()
+                .map(|(name, samples)| (name, Statistics::new(samples.into_weighted())))
+                .collect(),
             timings: value
                 .timings
                 .into_iter()
-                .fold(HashMap::default(), |mut map, (name, list)| {
-                    if let Ok(statistics) = Statistics::new(list) {
-                        map.insert(name, statistics);
-                    }
+                .map(|(name, reservoir)| (name, reservoir.into_statistics()))
+                .collect(),
+            summaries: value
+                .summaries
+                .into_iter()
+                .map(|(name, list)| (name, Statistics::new(list)))
+                .collect(),
+        }
+    }
+}
 
-                    map
-                }),
-        })
+/// Counter samples. Once there are `run_length_threshold` raw samples, they are sorted and
+/// collapsed into `(value, count)` runs if that takes less space, while later samples extend the
+/// last run or start a new one.
+#[derive(Debug, PartialEq)]
+enum Samples {
+    Raw(Vec<f64>),
+    RunLength(Vec<(f64, u64)>),
+}
+
+impl Default for Samples {
+    fn default() -> Self {
+        Samples::Raw(vec![])
+    }
+}
+
+impl Samples {
+    fn with_capacity(capacity: usize) -> Self {
+        Samples::Raw(Vec::with_capacity(capacity))
+    }
+
+    fn push(&mut self, value: f64, run_length_threshold: Option<usize>) {
+        match self {
+            Samples::Raw(list) => {
+                list.push(value);
+
+                if Some(list.len()) != run_length_threshold {
+                    return;
+                }
+
+                list.sort_by(f64::total_cmp);
+
+                let runs = list
+                    .iter()
+                    .fold(vec![], |mut runs: Vec<(f64, u64)>, value| {
+                        match runs.last_mut() {
+                            Some((last, count)) if last == value => *count += 1,
+                            _ => runs.push((*value, 1)),
+                        }
+
+                        runs
+                    });
+
+                if runs.len() * 2 < list.len() {
+                    *self = Samples::RunLength(runs);
+                }
+            }
+            Samples::RunLength(runs) => match runs.last_mut() {
+                Some((last, count)) if *last == value => *count += 1,
+                _ => runs.push((value, 1)),
+            },
+        }
+    }
+
+    fn into_weighted(self) -> Vec<(f64, u64)> {
+        match self {
+            Samples::Raw(list) => list.into_iter().map(|value| (value, 1)).collect(),
+            Samples::RunLength(runs) => runs,
+        }
+    }
+}
+
+/// Exact count, sum, min and max of observations, kept once samples are only a random sample of
+/// them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Totals {
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Totals {
+    fn of(list: &[(f64, u64)]) -> Self {
+        list.iter().fold(
+            Self {
+                count: 0,
+                sum: 0.,
+                min: f64::INFINITY,
+                max: f64::NEG_INFINITY,
+            },
+            |mut totals, (value, weight)| {
+                totals.observe(*value, *weight);
+
+                totals
+            },
+        )
+    }
+
+    fn observe(&mut self, value: f64, weight: u64) {
+        self.count = self.count.saturating_add(weight);
+        self.sum += value * weight as f64;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+}
+
+/// Sampling priority of a timing sample, compared in total order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Priority(f64);
+
+impl Eq for Priority {}
+
+impl PartialOrd for Priority {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Priority {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
     }
 }
 
+/// Timing samples. With a `size`, only `size` samples are kept, picked by priority sampling,
+/// where every sample gets priority `weight / u` for uniformly random `u` in `(0, 1]`, and those
+/// with highest priorities are kept. Once a sample was dropped, kept samples stand for
+/// `max(weight, threshold)` observations, where threshold is the highest priority dropped, which
+/// estimates weights without bias, so heavy samples are never outweighed by light ones.
+#[derive(Debug, Default)]
+struct Reservoir {
+    samples: Vec<(f64, u64)>,
+    /// Priority of every sample along with its index, lowest first. Only kept with a size.
+    priorities: BinaryHeap<Reverse<(Priority, usize)>>,
+    /// Highest priority of a dropped sample.
+    threshold: f64,
+    /// Set once samples are only a random sample of observations.
+    totals: Option<Totals>,
+}
+
+impl Reservoir {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            samples: Vec::with_capacity(capacity),
+            ..Default::default()
+        }
+    }
+
+    fn is_full(&self, size: Option<NonZeroUsize>) -> bool {
+        size.is_some_and(|size| self.samples.len() >= size.get())
+    }
+
+    fn push(&mut self, value: f64, weight: u64, size: Option<NonZeroUsize>) {
+        let Some(size) = size else {
+            self.samples.push((value, weight));
+
+            return;
+        };
+
+        let priority = Priority(weight as f64 / (1. - rand::thread_rng().gen::<f64>()));
+
+        if self.samples.len() < size.get() {
+            self.priorities
+                .push(Reverse((priority, self.samples.len())));
+            self.samples.push((value, weight));
+
+            return;
+        }
+
+        self.totals
+            .get_or_insert_with(|| Totals::of(&self.samples))
+            .observe(value, weight);
+
+        let mut lowest = self
+            .priorities
+            .peek_mut()
+            .expect("Full reservoir has priorities");
+        let Reverse((lowest_priority, index)) = *lowest;
+
+        if priority > lowest_priority {
+            self.threshold = self.threshold.max(lowest_priority.0);
+            self.samples[index] = (value, weight);
+
+            *lowest = Reverse((priority, index));
+        } else {
+            self.threshold = self.threshold.max(priority.0);
+        }
+    }
+
+    fn into_statistics(self) -> Statistics {
+        let Some(totals) = self.totals else {
+            return Statistics::new(self.samples);
+        };
+
+        let threshold = self.threshold.round() as u64;
+
+        Statistics::sampled(
+            self.samples
+                .into_iter()
+                .map(|(value, weight)| (value, weight.max(threshold)))
+                .collect(),
+            totals,
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub run_length_threshold: Option<usize>,
+    pub expected_samples_per_metric: Option<usize>,
+    /// Soft cap on estimated memory in bytes, see [`Registry::needs_flush`].
+    pub max_memory: Option<usize>,
+    /// Counters, timings, summaries, sets and histograms are published every this many cycles,
+    /// while gauges are published every cycle.
+    pub statistics_cycles: Option<u32>,
+    /// Prefix of metrics describing metco itself, like `metco.memory_shed`.
+    pub self_metrics_prefix: String,
+    /// Increasing upper bounds of histogram buckets, on top of which there is the `+Inf` bucket.
+    pub histogram_buckets: Vec<f64>,
+    /// Hard cap on number of names held by the registry, new names over it are rejected.
+    pub max_metrics: Option<usize>,
+    /// Gauges not updated for this long are not carried over to the next cycle.
+    pub gauge_ttl: Option<Duration>,
+    /// Counters published with zero count and sum in cycles without samples.
+    pub known_counters: Vec<String>,
+    /// Timings published with zero count and sum in cycles without samples.
+    pub known_timings: Vec<String>,
+    /// Keeps running totals of counters, which only some backends publish.
+    pub cumulative_counters: bool,
+    /// Running total of a counter not seen for more than this many cycles is dropped.
+    pub cumulative_counters_idle_cycles: u32,
+    /// Max number of samples kept per timing, over which a weighted random sample is kept.
+    pub timing_reservoir_size: Option<NonZeroUsize>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            run_length_threshold: None,
+            expected_samples_per_metric: None,
+            max_memory: None,
+            statistics_cycles: None,
+            self_metrics_prefix: "metco".to_string(),
+            max_metrics: None,
+            gauge_ttl: None,
+            known_counters: vec![],
+            known_timings: vec![],
+            timing_reservoir_size: None,
+            cumulative_counters: false,
+            cumulative_counters_idle_cycles: 1440,
+            histogram_buckets: vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10.],
+        }
+    }
+}
+
+/// Rough memory estimate of a map entry, on top of the name itself.
+const ENTRY_SIZE: usize = 64;
+/// Rough memory estimate of a single sample.
+const SAMPLE_SIZE: usize = 16;
+
 #[derive(Debug, Default)]
 pub struct Registry {
-    counters: HashMap<String, Vec<u64>>,
-    gauges: HashMap<String, i64>,
-    timings: HashMap<String, Vec<u64>>,
+    counters: HashMap<String, Samples>,
+    gauges: HashMap<String, f64>,
+    /// When every gauge was last updated, only tracked with `gauge_ttl` set.
+    gauges_updated: HashMap<String, Instant>,
+    timings: HashMap<String, Reservoir>,
+    summaries: HashMap<String, Vec<(f64, u64)>>,
+    sets: HashMap<String, HashSet<String>>,
+    histograms: HashMap<String, Histogram>,
+    /// Running total of every counter, along with number of cycles since it was last seen.
+    cumulative_counters: HashMap<String, (f64, u32)>,
+    /// Last reported total of every absolute counter.
+    absolutes: HashMap<String, f64>,
+    options: Options,
+    /// Estimated memory of everything in the registry.
+    memory: usize,
+    /// Estimated memory of gauges, counter totals and absolute counter totals carried over from the
+    /// previous cycle.
+    carried: usize,
+    /// Number of metrics dropped because memory cap was reached.
+    shed: u64,
+    /// Number of metrics dropped because cardinality cap was reached.
+    rejected: u64,
+    /// Number of cycles samples were carried over for.
+    cycle: u32,
 }
 
 impl Registry {
-    pub fn add(&mut self, metric: &Metric) -> bool {
-        match &metric.kind {
-            MetricKind::Counter(value) => self
-                .counters
-                .entry(metric.name.clone())
-                .or_default()
-                .push(*value),
-            MetricKind::Timing(value, resolution) => {
-                self.timings.entry(metric.name.clone()).or_default().push(
-                    value
-                        * match resolution {
-                            TimerResolution::Seconds => 1_000_000_000,
-                            TimerResolution::MilliSeconds => 1_000_000,
-                            TimerResolution::MicroSeconds => 1_000,
-                            TimerResolution::NanoSeconds => 1,
-                        },
-                )
+    pub fn new(options: Options) -> Self {
+        Self {
+            options,
+            ..Default::default()
+        }
+    }
+
+    /// Registry starting with gauges kept from a previous run.
+    pub fn with_gauges(options: Options, gauges: HashMap<String, f64>) -> Self {
+        let carried = gauges.keys().map(|name| ENTRY_SIZE + name.len()).sum();
+        let now = Instant::now();
+        let gauges_updated = match options.gauge_ttl {
+            Some(_) => gauges.keys().map(|name| (name.clone(), now)).collect(),
+            None => HashMap::new(),
+        };
+
+        Self {
+            gauges,
+            gauges_updated,
+            options,
+            memory: carried,
+            carried,
+            ..Default::default()
+        }
+    }
+
+    pub fn gauges(&self) -> &HashMap<String, f64> {
+        &self.gauges
+    }
+
+    /// Accounts for memory the metric takes. Once estimated memory reaches `max_memory`, metrics
+    /// with names not yet seen in the cycle are shed and counted in `metco.memory_shed`, while
+    /// known ones are still aggregated.
+    fn admit(&mut self, metric: &Metric) -> bool {
+        let (is_new, sample_size) = match &metric.kind {
+            MetricKind::Counter(_) => (!self.counters.contains_key(&metric.name), SAMPLE_SIZE),
+            MetricKind::Timing(..) => match self.timings.get(&metric.name) {
+                None => (true, SAMPLE_SIZE),
+                Some(reservoir) if reservoir.is_full(self.options.timing_reservoir_size) => {
+                    (false, 0)
+                }
+                Some(_) => (false, SAMPLE_SIZE),
+            },
+            MetricKind::Gauge(GaugeOperation::Summary(_)) => {
+                (!self.summaries.contains_key(&metric.name), SAMPLE_SIZE)
             }
-            MetricKind::Gauge(operation) => match operation {
-                GaugeOperation::Set(value) => {
-                    self.gauges.insert(metric.name.clone(), *value);
+            MetricKind::Gauge(GaugeOperation::Remove) => (false, 0),
+            MetricKind::Gauge(_) => (!self.gauges.contains_key(&metric.name), 0),
+            MetricKind::Absolute(_) => (!self.absolutes.contains_key(&metric.name), 0),
+            MetricKind::Histogram(_) => match self.histograms.contains_key(&metric.name) {
+                false => (
+                    true,
+                    SAMPLE_SIZE * (self.options.histogram_buckets.len() + 1),
+                ),
+                true => (false, 0),
+            },
+            MetricKind::Set(value) => match self.sets.get(&metric.name) {
+                None => (true, SAMPLE_SIZE + value.len()),
+                Some(values) if values.contains(value) => (false, 0),
+                Some(_) => (false, SAMPLE_SIZE + value.len()),
+            },
+        };
+
+        if is_new {
+            if self.is_over_memory_cap() {
+                log::trace!("Shedding metric {} over memory cap", metric.name);
+
+                self.shed += 1;
+
+                return false;
+            }
+
+            if self.is_over_cardinality_cap() {
+                if self.rejected == 0 {
+                    log::warn!(
+                        "Registry holds max-metrics names, rejecting new ones until the next flush"
+                    );
+                }
+
+                log::trace!("Rejecting metric {} over cardinality cap", metric.name);
+
+                self.rejected += 1;
+
+                return false;
+            }
+
+            self.memory += ENTRY_SIZE + metric.name.len();
+        }
+
+        self.memory += sample_size;
+
+        true
+    }
+
+    fn is_over_cardinality_cap(&self) -> bool {
+        self.options.max_metrics.is_some_and(|max_metrics| {
+            self.counters.len()
+                + self.gauges.len()
+                + self.timings.len()
+                + self.summaries.len()
+                + self.sets.len()
+                + self.histograms.len()
+                + self.absolutes.len()
+                >= max_metrics
+        })
+    }
+
+    fn is_over_memory_cap(&self) -> bool {
+        self.options
+            .max_memory
+            .is_some_and(|max_memory| self.memory >= max_memory)
+    }
+
+    /// Whether estimated memory reached the cap with metrics collected in this cycle, so the
+    /// registry should be flushed before the refresh interval elapses.
+    pub fn needs_flush(&self) -> bool {
+        self.is_over_memory_cap() && self.memory > self.carried
+    }
+
+    pub fn add(&mut self, metric: &Metric) -> Added {
+        if let MetricKind::Counter(value)
+        | MetricKind::Absolute(value)
+        | MetricKind::Histogram(value)
+        | MetricKind::Gauge(
+            GaugeOperation::Set(value)
+            | GaugeOperation::Modify(value)
+            | GaugeOperation::Summary(value),
+        ) = &metric.kind
+        {
+            if !value.is_finite() {
+                log::warn!("Ignoring non-finite value of metric {}", metric.name);
+
+                return Added::Rejected;
+            }
+        }
+
+        if let MetricKind::Gauge(GaugeOperation::ModifyClamped(value, min)) = &metric.kind {
+            if !value.is_finite() || !min.is_finite() {
+                log::warn!("Ignoring non-finite value of metric {}", metric.name);
+
+                return Added::Rejected;
+            }
+        }
+
+        if !self.admit(metric) {
+            return Added::Rejected;
+        }
+
+        let capacity = self.options.expected_samples_per_metric.unwrap_or(0);
+
+        match &metric.kind {
+            MetricKind::Counter(value) => {
+                self.counters
+                    .entry(metric.name.clone())
+                    .or_insert_with(|| Samples::with_capacity(capacity))
+                    .push(*value, self.options.run_length_threshold);
+
+                if self.options.cumulative_counters {
+                    let (total, idle_cycles) = self
+                        .cumulative_counters
+                        .entry(metric.name.clone())
+                        .or_default();
+
+                    *total += value;
+                    *idle_cycles = 0;
                 }
-                GaugeOperation::Modify(value) => {
-                    let val = self.gauges.entry(metric.name.clone()).or_default();
+            }
+            MetricKind::Timing(value, resolution, weight) => {
+                let size = self.options.timing_reservoir_size;
+
+                self.timings
+                    .entry(metric.name.clone())
+                    .or_insert_with(|| {
+                        Reservoir::with_capacity(
+                            size.map_or(capacity, |size| capacity.min(size.get())),
+                        )
+                    })
+                    .push(*value as f64 * resolution.nanoseconds(), *weight, size)
+            }
+            MetricKind::Gauge(operation) => {
+                match operation {
+                    GaugeOperation::Set(value) => {
+                        self.gauges.insert(metric.name.clone(), *value);
+                    }
+                    GaugeOperation::Modify(value) => {
+                        let val = self.gauges.entry(metric.name.clone()).or_default();
+
+                        match *val + value {
+                            res if !res.is_finite() => return Added::Overflow,
+                            res => *val = res,
+                        }
+                    }
+                    GaugeOperation::ModifyClamped(value, min) => {
+                        let val = self.gauges.entry(metric.name.clone()).or_default();
 
-                    match val.checked_add(*value) {
-                        None => return false,
-                        Some(res) => *val = res,
+                        match *val + value {
+                            res if !res.is_finite() => return Added::Overflow,
+                            res => *val = res.max(*min),
+                        }
+                    }
+                    GaugeOperation::Remove => {
+                        self.gauges.remove(&metric.name);
+                        self.gauges_updated.remove(&metric.name);
+
+                        return Added::Aggregated;
+                    }
+                    GaugeOperation::Summary(value) => {
+                        self.gauges.insert(metric.name.clone(), *value);
+
+                        self.summaries
+                            .entry(metric.name.clone())
+                            .or_insert_with(|| Vec::with_capacity(capacity))
+                            .push((*value, 1));
                     }
                 }
-                GaugeOperation::Remove => {
-                    self.gauges.remove(&metric.name);
+
+                if self.options.gauge_ttl.is_some() {
+                    self.gauges_updated
+                        .insert(metric.name.clone(), Instant::now());
                 }
-            },
+            }
+            MetricKind::Set(value) => {
+                self.sets
+                    .entry(metric.name.clone())
+                    .or_default()
+                    .insert(value.clone());
+            }
+            MetricKind::Histogram(value) => self
+                .histograms
+                .entry(metric.name.clone())
+                .or_insert_with(|| Histogram::new(self.options.histogram_buckets.clone()))
+                .observe(*value),
+            MetricKind::Absolute(value) => {
+                let Some(previous) = self.absolutes.insert(metric.name.clone(), *value) else {
+                    // First report only establishes the total to count from
+                    return Added::Aggregated;
+                };
+
+                // Total lower than the previous one means the source was reset and counted from
+                // zero again
+                let delta = if *value >= previous {
+                    value - previous
+                } else {
+                    *value
+                };
+
+                return self.add(&Metric {
+                    name: metric.name.clone(),
+                    kind: MetricKind::Counter(delta),
+                });
+            }
         }
 
-        true
+        Added::Aggregated
     }
 
-    pub fn new_with_gauges(&self) -> Self {
-        Self {
-            gauges: self.gauges.clone(),
+    /// Whether this cycle publishes statistics, which is every `statistics_cycles` cycles, or
+    /// earlier once memory cap is reached since carrying samples over would not free any memory.
+    fn is_statistics_cycle(&self) -> bool {
+        self.cycle + 1 >= self.options.statistics_cycles.unwrap_or(1) || self.is_over_memory_cap()
+    }
+
+    /// Registry for the next cycle, which carries over gauges, counter running totals and absolute
+    /// counter totals. Unless this cycle publishes statistics, samples are moved to the next
+    /// registry as well, so only gauges are left to be published from this one.
+    pub fn new_with_carryover(&mut self) -> Self {
+        self.carryover_at(Instant::now())
+    }
+
+    /// Registry for the next cycle as of `now`, which leaves out gauges that expired by then.
+    fn carryover_at(&mut self, now: Instant) -> Self {
+        let mut gauges = self.gauges.clone();
+        let mut gauges_updated = self.gauges_updated.clone();
+
+        if let Some(gauge_ttl) = self.options.gauge_ttl {
+            gauges_updated.retain(|name, updated| {
+                let is_fresh = now.saturating_duration_since(*updated) <= gauge_ttl;
+
+                if !is_fresh {
+                    log::debug!("Gauge {name} was not updated within gauge-ttl, dropping it");
+
+                    gauges.remove(name);
+                }
+
+                is_fresh
+            });
+        }
+
+        let cumulative_counters = self
+            .cumulative_counters
+            .iter()
+            .filter(|(_, (_, idle_cycles))| {
+                *idle_cycles < self.options.cumulative_counters_idle_cycles
+            })
+            .map(|(name, (total, idle_cycles))| (name.clone(), (*total, idle_cycles + 1)))
+            .collect::<HashMap<_, _>>();
+
+        let carried = gauges
+            .keys()
+            .chain(cumulative_counters.keys())
+            .chain(self.absolutes.keys())
+            .map(|name| ENTRY_SIZE + name.len())
+            .sum();
+
+        let mut next = Self {
+            gauges,
+            gauges_updated,
+            cumulative_counters,
+            absolutes: self.absolutes.clone(),
+            options: self.options.clone(),
+            memory: carried,
+            carried,
             ..Default::default()
+        };
+
+        if !self.is_statistics_cycle() {
+            next.counters = std::mem::take(&mut self.counters);
+            next.timings = std::mem::take(&mut self.timings);
+            next.summaries = std::mem::take(&mut self.summaries);
+            next.sets = std::mem::take(&mut self.sets);
+            next.histograms = std::mem::take(&mut self.histograms);
+            next.memory = self.memory;
+            next.cycle = self.cycle + 1;
         }
+
+        next
     }
 
-    pub fn finalize(self) -> Option<TimeFrame> {
-        TimeFrame::try_from(self).ok()
+    pub fn finalize(self) -> TimeFrame {
+        TimeFrame::from(self)
     }
 }
 
@@ -192,26 +1008,39 @@ mod test {
 
     use super::*;
 
+    fn percentile(value: f64) -> Percentile {
+        Percentile::try_from(value).unwrap()
+    }
+
     #[test]
     fn counter_can_be_added() {
         let mut registry = Registry::default();
 
         let mut map = HashMap::default();
-        map.insert("test".into(), vec![2, 7]);
-        map.insert("demo".into(), vec![32]);
-
-        assert!(registry.add(&Metric {
-            name: "test".into(),
-            kind: MetricKind::Counter(2)
-        }));
-        assert!(registry.add(&Metric {
-            name: "demo".into(),
-            kind: MetricKind::Counter(32)
-        }));
-        assert!(registry.add(&Metric {
-            name: "test".into(),
-            kind: MetricKind::Counter(7)
-        }));
+        map.insert("test".into(), Samples::Raw(vec![2., 7.]));
+        map.insert("demo".into(), Samples::Raw(vec![32.]));
+
+        assert_eq!(
+            Added::Aggregated,
+            registry.add(&Metric {
+                name: "test".into(),
+                kind: MetricKind::Counter(2.)
+            })
+        );
+        assert_eq!(
+            Added::Aggregated,
+            registry.add(&Metric {
+                name: "demo".into(),
+                kind: MetricKind::Counter(32.)
+            })
+        );
+        assert_eq!(
+            Added::Aggregated,
+            registry.add(&Metric {
+                name: "test".into(),
+                kind: MetricKind::Counter(7.)
+            })
+        );
 
         assert_eq!(map, registry.counters)
     }
@@ -221,27 +1050,73 @@ mod test {
         let mut registry = Registry::default();
 
         let mut map = HashMap::default();
-        map.insert("test".into(), vec![2, 7_000]);
-        map.insert("demo".into(), vec![32_000_000, 64_000_000_000]);
-
-        assert!(registry.add(&Metric {
-            name: "test".into(),
-            kind: MetricKind::Timing(2, TimerResolution::NanoSeconds)
-        }));
-        assert!(registry.add(&Metric {
-            name: "demo".into(),
-            kind: MetricKind::Timing(32, TimerResolution::MilliSeconds)
-        }));
-        assert!(registry.add(&Metric {
-            name: "test".into(),
-            kind: MetricKind::Timing(7, TimerResolution::MicroSeconds)
-        }));
-        assert!(registry.add(&Metric {
-            name: "demo".into(),
-            kind: MetricKind::Timing(64, TimerResolution::Seconds)
-        }));
-
-        assert_eq!(map, registry.timings)
+        map.insert("test".into(), vec![(2., 1), (7_000., 3)]);
+        map.insert("demo".into(), vec![(32_000_000., 1), (64_000_000_000., 1)]);
+
+        assert_eq!(
+            Added::Aggregated,
+            registry.add(&Metric {
+                name: "test".into(),
+                kind: MetricKind::Timing(2, TimerResolution::NanoSeconds, 1)
+            })
+        );
+        assert_eq!(
+            Added::Aggregated,
+            registry.add(&Metric {
+                name: "demo".into(),
+                kind: MetricKind::Timing(32, TimerResolution::MilliSeconds, 1)
+            })
+        );
+        assert_eq!(
+            Added::Aggregated,
+            registry.add(&Metric {
+                name: "test".into(),
+                kind: MetricKind::Timing(7, TimerResolution::MicroSeconds, 3)
+            })
+        );
+        assert_eq!(
+            Added::Aggregated,
+            registry.add(&Metric {
+                name: "demo".into(),
+                kind: MetricKind::Timing(64, TimerResolution::Seconds, 1)
+            })
+        );
+
+        assert_eq!(
+            map,
+            registry
+                .timings
+                .into_iter()
+                .map(|(name, reservoir)| (name, reservoir.samples))
+                .collect::<HashMap<_, _>>()
+        )
+    }
+
+    #[test]
+    fn clamped_gauge_never_goes_below_floor() {
+        let mut registry = Registry::default();
+        let modify = |value, min| Metric {
+            name: "queue".into(),
+            kind: MetricKind::Gauge(GaugeOperation::ModifyClamped(value, min)),
+        };
+
+        assert_eq!(Added::Aggregated, registry.add(&modify(3., 0.)));
+        assert_eq!(3., registry.gauges["queue"]);
+
+        assert_eq!(Added::Aggregated, registry.add(&modify(-2., 0.)));
+        assert_eq!(1., registry.gauges["queue"]);
+
+        assert_eq!(Added::Aggregated, registry.add(&modify(-5., 0.)));
+        assert_eq!(0., registry.gauges["queue"]);
+
+        assert_eq!(Added::Aggregated, registry.add(&modify(2., 0.)));
+        assert_eq!(2., registry.gauges["queue"]);
+
+        assert_eq!(Added::Aggregated, registry.add(&modify(-10., -5.)));
+        assert_eq!(-5., registry.gauges["queue"]);
+
+        assert_eq!(Added::Rejected, registry.add(&modify(-1., f64::NAN)));
+        assert_eq!(-5., registry.gauges["queue"]);
     }
 
     #[test]
@@ -249,40 +1124,692 @@ mod test {
         let mut registry = Registry::default();
 
         let mut map = HashMap::default();
-        map.insert("test".into(), 10);
+        map.insert("test".into(), 10.);
 
-        assert!(registry.add(&Metric {
-            name: "test".into(),
-            kind: MetricKind::Gauge(GaugeOperation::Modify(10))
-        }));
+        assert_eq!(
+            Added::Aggregated,
+            registry.add(&Metric {
+                name: "test".into(),
+                kind: MetricKind::Gauge(GaugeOperation::Modify(10.))
+            })
+        );
 
         assert_eq!(map, registry.gauges);
 
         let mut map = HashMap::default();
-        map.insert("test".into(), -10);
+        map.insert("test".into(), -10.);
 
-        assert!(registry.add(&Metric {
-            name: "test".into(),
-            kind: MetricKind::Gauge(GaugeOperation::Modify(-20))
-        }));
+        assert_eq!(
+            Added::Aggregated,
+            registry.add(&Metric {
+                name: "test".into(),
+                kind: MetricKind::Gauge(GaugeOperation::Modify(-20.))
+            })
+        );
 
         assert_eq!(map, registry.gauges);
 
         let mut map = HashMap::default();
-        map.insert("test".into(), 32);
+        map.insert("test".into(), 32.);
 
-        assert!(registry.add(&Metric {
-            name: "test".into(),
-            kind: MetricKind::Gauge(GaugeOperation::Set(32))
-        }));
+        assert_eq!(
+            Added::Aggregated,
+            registry.add(&Metric {
+                name: "test".into(),
+                kind: MetricKind::Gauge(GaugeOperation::Set(32.))
+            })
+        );
 
         assert_eq!(map, registry.gauges);
 
-        assert!(registry.add(&Metric {
-            name: "test".into(),
-            kind: MetricKind::Gauge(GaugeOperation::Remove)
-        }));
+        assert_eq!(
+            Added::Aggregated,
+            registry.add(&Metric {
+                name: "test".into(),
+                kind: MetricKind::Gauge(GaugeOperation::Remove)
+            })
+        );
 
         assert_eq!(HashMap::default(), registry.gauges);
     }
+
+    #[test]
+    fn weighted_statistics_match_repeated_samples() {
+        let weighted = Statistics::new(vec![(10., 3), (1., 1), (4., 2)]);
+        let repeated = Statistics::new(vec![
+            (10., 1),
+            (1., 1),
+            (4., 1),
+            (10., 1),
+            (4., 1),
+            (10., 1),
+        ]);
+
+        assert_eq!(6, weighted.count());
+        assert_eq!(39., weighted.sum());
+        assert_eq!(Some(6.5), weighted.average());
+        assert_eq!(Some(7.), weighted.median());
+        assert_eq!(Some(1.), weighted.percentile(percentile(0.)));
+        assert_eq!(Some(4.), weighted.percentile(percentile(0.2)));
+        assert_eq!(Some(10.), weighted.percentile(percentile(0.5)));
+
+        assert_eq!(repeated.count(), weighted.count());
+        assert_eq!(repeated.sum(), weighted.sum());
+        assert_eq!(repeated.average(), weighted.average());
+        assert_eq!(repeated.std(), weighted.std());
+        assert_eq!(repeated.median(), weighted.median());
+
+        for p in [0., 0.1, 0.25, 0.5, 0.75, 0.9, 0.99] {
+            assert_eq!(
+                repeated.percentile(percentile(p)),
+                weighted.percentile(percentile(p))
+            );
+        }
+    }
+
+    #[test]
+    fn std_is_computed_only_when_used() {
+        let stats = Statistics::new(vec![(1., 1), (3., 1)]);
+
+        assert_eq!((2, 4., Some(3.)), (stats.count(), stats.sum(), stats.max()));
+        assert!(stats.std.get().is_none());
+
+        assert_eq!(Some(2f64.sqrt()), stats.std());
+        assert!(stats.std.get().is_some());
+    }
+
+    #[test]
+    fn overflowing_sum_saturates_instead_of_dropping_series() {
+        let mut registry = Registry::default();
+
+        for _ in 0..2 {
+            assert_eq!(
+                Added::Aggregated,
+                registry.add(&Metric {
+                    name: "abc".into(),
+                    kind: MetricKind::Gauge(GaugeOperation::Summary(f64::MAX)),
+                })
+            );
+        }
+
+        let time_frame = registry.finalize();
+
+        assert_eq!(2, time_frame.summaries["abc"].count());
+        assert_eq!(f64::MAX, time_frame.summaries["abc"].sum());
+        assert_eq!(Some(f64::MAX), time_frame.summaries["abc"].max());
+    }
+
+    #[test]
+    fn decimal_values_are_aggregated() {
+        let mut registry = Registry::default();
+
+        for value in [0.5, 1.25, 0.25] {
+            assert_eq!(
+                Added::Aggregated,
+                registry.add(&Metric {
+                    name: "abc".into(),
+                    kind: MetricKind::Counter(value),
+                })
+            );
+        }
+
+        assert_eq!(
+            Added::Rejected,
+            registry.add(&Metric {
+                name: "abc".into(),
+                kind: MetricKind::Counter(f64::NAN),
+            })
+        );
+
+        let stats = &registry.finalize().counters["abc"];
+
+        assert_eq!(3, stats.count());
+        assert_eq!(2., stats.sum());
+        assert_eq!(Some(0.25), stats.min());
+        assert_eq!(Some(0.5), stats.median());
+        assert_eq!(Some(1.25), stats.max());
+    }
+
+    #[test]
+    fn min_and_max_are_smallest_and_largest_values() {
+        let stats = Statistics::new(vec![(7., 1), (3., 2), (12., 1), (5., 4)]);
+
+        assert_eq!(Some(3.), stats.min());
+        assert_eq!(Some(12.), stats.max());
+
+        let stats = Statistics::new(vec![(4., 3)]);
+
+        assert_eq!(Some(4.), stats.min());
+        assert_eq!(Some(4.), stats.max());
+    }
+
+    #[test]
+    fn percentile_at_one_is_the_largest_value() {
+        let stats = Statistics::new(vec![(7., 1), (3., 2), (12., 1)]);
+
+        assert_eq!(Some(12.), stats.percentile(percentile(1.)));
+        assert_eq!(Some(3.), stats.percentile(percentile(0.)));
+
+        let stats = Statistics::new(vec![(4., 1)]);
+
+        assert_eq!(Some(4.), stats.percentile(percentile(1.)));
+    }
+
+    #[test]
+    fn percentile_must_be_within_unit_range() {
+        for value in [-0.1, 1.5, 95., f64::NAN, f64::INFINITY] {
+            assert!(Percentile::try_from(value).is_err(), "{value} is rejected");
+        }
+
+        assert_eq!("p0", percentile(0.).label());
+        assert_eq!("p50", percentile(0.5).label());
+        assert_eq!("p95", percentile(0.95).label());
+        assert_eq!("p99_9", percentile(0.999).label());
+        assert_eq!("p99_99", percentile(0.9999).label());
+        assert_eq!("p100", percentile(1.).label());
+    }
+
+    #[test]
+    fn run_length_counters_match_raw_counters() {
+        let mut raw = Registry::default();
+        let mut run_length = Registry::new(Options {
+            run_length_threshold: Some(4),
+            ..Default::default()
+        });
+
+        for value in [1., 1., 1., 1., 3., 1., 1., 3., 3., 1.] {
+            let metric = Metric {
+                name: "test".into(),
+                kind: MetricKind::Counter(value),
+            };
+
+            assert_eq!(Added::Aggregated, raw.add(&metric));
+            assert_eq!(Added::Aggregated, run_length.add(&metric));
+        }
+
+        assert_eq!(
+            Samples::RunLength(vec![(1., 4), (3., 1), (1., 2), (3., 2), (1., 1)]),
+            run_length.counters["test"]
+        );
+
+        let raw = &raw.finalize().counters["test"];
+        let run_length = &run_length.finalize().counters["test"];
+
+        assert_eq!(raw.count(), run_length.count());
+        assert_eq!(raw.sum(), run_length.sum());
+        assert_eq!(raw.average(), run_length.average());
+        assert_eq!(raw.std(), run_length.std());
+        assert_eq!(raw.median(), run_length.median());
+
+        for p in [0., 0.25, 0.5, 0.75, 0.9] {
+            assert_eq!(
+                raw.percentile(percentile(p)),
+                run_length.percentile(percentile(p))
+            );
+        }
+    }
+
+    #[test]
+    fn counters_stay_raw_when_run_length_does_not_help() {
+        let mut registry = Registry::new(Options {
+            run_length_threshold: Some(4),
+            ..Default::default()
+        });
+
+        for value in [1., 2., 3., 4., 5.] {
+            assert_eq!(
+                Added::Aggregated,
+                registry.add(&Metric {
+                    name: "test".into(),
+                    kind: MetricKind::Counter(value),
+                })
+            );
+        }
+
+        assert_eq!(
+            Samples::Raw(vec![1., 2., 3., 4., 5.]),
+            registry.counters["test"]
+        );
+    }
+
+    #[test]
+    fn samples_are_preallocated_for_new_metrics() {
+        let mut registry = Registry::new(Options {
+            expected_samples_per_metric: Some(64),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            Added::Aggregated,
+            registry.add(&Metric {
+                name: "counter".into(),
+                kind: MetricKind::Counter(1.),
+            })
+        );
+        assert_eq!(
+            Added::Aggregated,
+            registry.add(&Metric {
+                name: "timing".into(),
+                kind: MetricKind::Timing(1, TimerResolution::MilliSeconds, 1),
+            })
+        );
+
+        match &registry.counters["counter"] {
+            Samples::Raw(list) => assert!(list.capacity() >= 64),
+            samples => panic!("Expected raw samples, got {:?}", samples),
+        }
+
+        assert!(registry.timings["timing"].samples.capacity() >= 64);
+    }
+
+    #[test]
+    fn gauges_not_updated_within_ttl_are_dropped() {
+        let gauge = |name: &str, value| Metric {
+            name: name.into(),
+            kind: MetricKind::Gauge(GaugeOperation::Set(value)),
+        };
+
+        let mut registry = Registry::new(Options {
+            gauge_ttl: Some(Duration::from_secs(60)),
+            ..Default::default()
+        });
+        let start = Instant::now();
+
+        assert_eq!(Added::Aggregated, registry.add(&gauge("stale", 1.)));
+        assert_eq!(Added::Aggregated, registry.add(&gauge("fresh", 2.)));
+
+        let mut next = registry.carryover_at(start + Duration::from_secs(30));
+
+        assert_eq!(2, next.gauges().len());
+
+        next.gauges_updated
+            .insert("fresh".into(), start + Duration::from_secs(50));
+
+        let next = next.carryover_at(start + Duration::from_secs(90));
+
+        assert_eq!(&HashMap::from([("fresh".into(), 2.)]), next.gauges());
+
+        let mut forever = Registry::default();
+
+        assert_eq!(Added::Aggregated, forever.add(&gauge("abc", 1.)));
+
+        let next = forever.carryover_at(start + Duration::from_secs(86400));
+
+        assert_eq!(&HashMap::from([("abc".into(), 1.)]), next.gauges());
+    }
+
+    #[test]
+    fn summary_gauges_collect_samples_and_persist_last_value() {
+        let mut registry = Registry::default();
+
+        for value in [4., 8., 6.] {
+            assert_eq!(
+                Added::Aggregated,
+                registry.add(&Metric {
+                    name: "abc".into(),
+                    kind: MetricKind::Gauge(GaugeOperation::Summary(value)),
+                })
+            );
+        }
+
+        let next = registry.new_with_carryover();
+        let time_frame = registry.finalize();
+
+        assert_eq!(6., time_frame.gauges["abc"]);
+
+        let summary = &time_frame.summaries["abc"];
+
+        assert_eq!(3, summary.count());
+        assert_eq!(18., summary.sum());
+        assert_eq!(Some(6.), summary.average());
+        assert_eq!(Some(6.), summary.median());
+
+        let time_frame = next.finalize();
+
+        assert_eq!(6., time_frame.gauges["abc"]);
+        assert!(time_frame.summaries.is_empty());
+    }
+
+    #[test]
+    fn sets_count_distinct_values_per_cycle() {
+        let mut registry = Registry::default();
+
+        for value in ["alice", "bob", "alice"] {
+            assert_eq!(
+                Added::Aggregated,
+                registry.add(&Metric {
+                    name: "users".into(),
+                    kind: MetricKind::Set(value.into()),
+                })
+            );
+        }
+
+        let next = registry.new_with_carryover();
+
+        assert_eq!(2, registry.finalize().sets["users"]);
+        assert!(next.finalize().sets.is_empty());
+    }
+
+    #[test]
+    fn absolute_counters_count_increase_across_cycles() {
+        let absolute = |value: f64| Metric {
+            name: "rx_bytes".into(),
+            kind: MetricKind::Absolute(value),
+        };
+
+        let mut registry = Registry::default();
+
+        assert_eq!(Added::Aggregated, registry.add(&absolute(100.)));
+
+        let mut next = registry.new_with_carryover();
+
+        assert!(registry.finalize().counters.is_empty());
+
+        assert_eq!(Added::Aggregated, next.add(&absolute(150.)));
+        assert_eq!(Added::Aggregated, next.add(&absolute(180.)));
+
+        registry = next;
+        next = registry.new_with_carryover();
+
+        let counter = &registry.finalize().counters["rx_bytes"];
+
+        assert_eq!((2, 80.), (counter.count(), counter.sum()));
+
+        // Source was reset, so the new total is counted as is
+        assert_eq!(Added::Aggregated, next.add(&absolute(40.)));
+        assert_eq!(Added::Aggregated, next.add(&absolute(40.)));
+
+        let counter = &next.finalize().counters["rx_bytes"];
+
+        assert_eq!(
+            (2, 40., Some(0.)),
+            (counter.count(), counter.sum(), counter.min())
+        );
+    }
+
+    #[test]
+    fn histogram_observations_are_bucketed_up_to_bound() {
+        let mut registry = Registry::new(Options {
+            histogram_buckets: vec![1., 5.],
+            ..Default::default()
+        });
+
+        for value in [0., 0.5, 1., 1.001, 4.999, 5., 5.001, 100.] {
+            assert_eq!(
+                Added::Aggregated,
+                registry.add(&Metric {
+                    name: "latency".into(),
+                    kind: MetricKind::Histogram(value),
+                })
+            );
+        }
+
+        let histogram = &registry.finalize().histograms["latency"];
+
+        assert_eq!(8, histogram.count());
+        assert_eq!(117.501, histogram.sum());
+        assert_eq!(
+            vec![(1., 3), (5., 6), (f64::INFINITY, 8)],
+            histogram.buckets()
+        );
+    }
+
+    #[test]
+    fn statistics_are_carried_over_until_their_cycle() {
+        let mut registry = Registry::new(Options {
+            statistics_cycles: Some(3),
+            ..Default::default()
+        });
+
+        for cycle in 1..=6 {
+            assert_eq!(
+                Added::Aggregated,
+                registry.add(&Metric {
+                    name: "abc".into(),
+                    kind: MetricKind::Gauge(GaugeOperation::Set(cycle as f64)),
+                })
+            );
+            assert_eq!(
+                Added::Aggregated,
+                registry.add(&Metric {
+                    name: "abc".into(),
+                    kind: MetricKind::Timing(cycle as u64, TimerResolution::NanoSeconds, 1),
+                })
+            );
+
+            let next = registry.new_with_carryover();
+            let time_frame = registry.finalize();
+
+            assert_eq!(cycle as f64, time_frame.gauges["abc"]);
+
+            if cycle % 3 == 0 {
+                assert_eq!(3, time_frame.timings["abc"].count());
+                assert_eq!(Some(cycle as f64), time_frame.timings["abc"].max());
+            } else {
+                assert!(time_frame.timings.is_empty());
+            }
+
+            registry = next;
+        }
+    }
+
+    #[test]
+    fn new_names_are_shed_over_memory_cap() {
+        let counter = |name: &str| Metric {
+            name: name.into(),
+            kind: MetricKind::Counter(1.),
+        };
+
+        let mut registry = Registry::new(Options {
+            max_memory: Some(2 * (ENTRY_SIZE + 1 + SAMPLE_SIZE)),
+            ..Default::default()
+        });
+
+        assert_eq!(Added::Aggregated, registry.add(&counter("a")));
+        assert!(!registry.needs_flush());
+        assert_eq!(Added::Aggregated, registry.add(&counter("b")));
+        assert!(registry.needs_flush());
+
+        assert_eq!(Added::Rejected, registry.add(&counter("c")));
+        assert_eq!(Added::Rejected, registry.add(&counter("d")));
+        assert_eq!(Added::Aggregated, registry.add(&counter("a")));
+
+        let time_frame = registry.finalize();
+
+        assert_eq!(2, time_frame.counters["a"].count());
+        assert_eq!(1, time_frame.counters["b"].count());
+        assert!(!time_frame.counters.contains_key("c"));
+        assert!(!time_frame.counters.contains_key("d"));
+        assert_eq!(2., time_frame.counters["metco.memory_shed"].sum());
+    }
+
+    #[test]
+    fn new_names_are_rejected_over_cardinality_cap() {
+        let mut registry = Registry::new(Options {
+            max_metrics: Some(3),
+            ..Default::default()
+        });
+
+        for name in ["a", "b", "c"] {
+            assert_eq!(
+                Added::Aggregated,
+                registry.add(&Metric {
+                    name: name.into(),
+                    kind: MetricKind::Counter(1.),
+                })
+            );
+        }
+
+        let rejected = Metric {
+            name: "d".into(),
+            kind: MetricKind::Gauge(GaugeOperation::Set(1.)),
+        };
+
+        assert_eq!(Added::Rejected, registry.add(&rejected));
+        assert!(!registry.needs_flush());
+        assert_eq!(
+            Added::Aggregated,
+            registry.add(&Metric {
+                name: "a".into(),
+                kind: MetricKind::Counter(1.),
+            })
+        );
+
+        let time_frame = registry.finalize();
+
+        assert_eq!(2, time_frame.counters["a"].count());
+        assert!(!time_frame.gauges.contains_key("d"));
+        assert_eq!(1., time_frame.counters["metco.cardinality_rejected"].sum());
+    }
+
+    #[test]
+    fn timing_samples_stay_within_reservoir_size() {
+        let mut registry = Registry::new(Options {
+            timing_reservoir_size: NonZeroUsize::new(100),
+            ..Default::default()
+        });
+
+        for value in 1..=10_000 {
+            assert_eq!(
+                Added::Aggregated,
+                registry.add(&Metric {
+                    name: "latency".into(),
+                    kind: MetricKind::Timing(value, TimerResolution::NanoSeconds, 1 + value % 2),
+                })
+            );
+
+            assert!(registry.timings["latency"].samples.len() <= 100);
+        }
+
+        let memory = registry.memory;
+
+        registry.add(&Metric {
+            name: "latency".into(),
+            kind: MetricKind::Timing(1, TimerResolution::NanoSeconds, 1),
+        });
+
+        assert_eq!(memory, registry.memory);
+
+        let time_frame = registry.finalize();
+        let statistics = &time_frame.timings["latency"];
+
+        assert_eq!(15_001, statistics.count());
+        assert_eq!(75_005_001., statistics.sum());
+        assert_eq!(Some(1.), statistics.min());
+        assert_eq!(Some(10_000.), statistics.max());
+        assert!((1. ..=10_000.).contains(&statistics.median().unwrap()));
+    }
+
+    #[test]
+    fn heavy_timing_samples_keep_their_weight_in_reservoir() {
+        let mut registry = Registry::new(Options {
+            timing_reservoir_size: NonZeroUsize::new(10),
+            ..Default::default()
+        });
+        let timing = |value, weight| Metric {
+            name: "latency".into(),
+            kind: MetricKind::Timing(value, TimerResolution::NanoSeconds, weight),
+        };
+
+        registry.add(&timing(1_000, 1_000_000));
+
+        for _ in 0..10_000 {
+            registry.add(&timing(1, 1));
+        }
+
+        let time_frame = registry.finalize();
+        let statistics = &time_frame.timings["latency"];
+
+        assert_eq!(1_010_000, statistics.count());
+        assert_eq!(Some(1_000.), statistics.median());
+        assert_eq!(Some(1.), statistics.min());
+    }
+
+    #[test]
+    fn summary_is_computed_from_time_frame() {
+        let mut registry = Registry::default();
+
+        for (name, kind) in [
+            ("a", MetricKind::Counter(1.)),
+            ("a", MetricKind::Counter(2.)),
+            ("b", MetricKind::Counter(1.)),
+            ("t", MetricKind::Timing(5, TimerResolution::MilliSeconds, 3)),
+            ("g", MetricKind::Gauge(GaugeOperation::Set(1.))),
+            ("s", MetricKind::Gauge(GaugeOperation::Summary(1.))),
+            ("h", MetricKind::Histogram(0.5)),
+            ("u", MetricKind::Set("x".into())),
+        ] {
+            registry.add(&Metric {
+                name: name.into(),
+                kind,
+            });
+        }
+
+        assert_eq!(
+            Summary {
+                counters: 2,
+                timings: 1,
+                gauges: 2,
+                observations: 8,
+            },
+            registry.finalize().summary()
+        );
+    }
+
+    #[test]
+    fn idle_cumulative_counters_are_dropped() {
+        let counter = |name: &str| Metric {
+            name: name.into(),
+            kind: MetricKind::Counter(1.),
+        };
+
+        let mut registry = Registry::default();
+
+        registry.add(&counter("a"));
+
+        assert!(registry.cumulative_counters.is_empty());
+
+        let mut registry = Registry::new(Options {
+            cumulative_counters: true,
+            cumulative_counters_idle_cycles: 2,
+            ..Default::default()
+        });
+
+        registry.add(&counter("a"));
+        registry.add(&counter("b"));
+
+        for _ in 0..2 {
+            registry = registry.new_with_carryover();
+            registry.add(&counter("a"));
+        }
+
+        assert_eq!(1., registry.cumulative_counters["b"].0);
+
+        registry = registry.new_with_carryover();
+
+        assert_eq!(3., registry.cumulative_counters["a"].0);
+        assert!(!registry.cumulative_counters.contains_key("b"));
+        assert_eq!(
+            registry.carried,
+            registry
+                .gauges
+                .keys()
+                .chain(registry.cumulative_counters.keys())
+                .map(|name| ENTRY_SIZE + name.len())
+                .sum::<usize>()
+        );
+    }
+
+    #[test]
+    fn empty_statistics_have_only_count_and_sum() {
+        let stats = Statistics::new(vec![]);
+
+        assert_eq!((0, 0.), (stats.count(), stats.sum()));
+        assert_eq!(None, stats.average());
+        assert_eq!(None, stats.std());
+        assert_eq!(None, stats.min());
+        assert_eq!(None, stats.max());
+        assert_eq!(None, stats.median());
+        assert_eq!(None, stats.percentile(percentile(0.9)));
+    }
 }
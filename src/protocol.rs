@@ -1,41 +1,147 @@
 use nom::branch::alt;
-use nom::bytes::complete::{escaped_transform, is_not, tag};
+use nom::bytes::complete::{escaped_transform, is_not, tag, take};
 use nom::character::complete::{char, digit1};
-use nom::combinator::{map, map_res, recognize, value};
-use nom::multi::separated_list1;
-use nom::sequence::tuple;
+use nom::combinator::{cut, eof, map, map_opt, map_res, opt, peek, recognize, value, verify};
+use nom::multi::{many0, separated_list1};
+use nom::number::complete::{be_i64, be_u16, be_u64, be_u8};
+use nom::sequence::{preceded, terminated, tuple};
 use nom::IResult;
 
 use crate::metrics::{GaugeOperation, Metric, MetricKind, TimerResolution};
 
-fn parse_counter(input: &str) -> IResult<&str, MetricKind> {
-    let (input, _) = tag("c|")(input)?;
+/// Defaults are values used when a metric is sent without one, like `abc|c`. Kinds with no
+/// default require an explicit value.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub counter_default: u64,
+    pub timing_default: Option<u64>,
+    pub gauge_default: Option<i64>,
+    pub trim_names: bool,
+    /// Longest name in bytes, longer ones are dropped.
+    pub max_name_length: Option<usize>,
+    /// Prepended to every name with a `.` separator, unless empty. It's taken literally, after
+    /// names are unescaped.
+    pub prefix: String,
+}
 
-    fn into_u64(input: &str) -> Result<MetricKind, std::num::ParseIntError> {
-        Ok(MetricKind::Counter(input.parse::<u64>()?))
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            counter_default: 1,
+            timing_default: None,
+            gauge_default: None,
+            trim_names: false,
+            max_name_length: None,
+            prefix: String::new(),
+        }
     }
+}
+
+fn end_of_metric(input: &str) -> IResult<&str, ()> {
+    value((), peek(alt((eof, tag("\n")))))(input)
+}
+
+fn omitted_value<'a, T: Clone>(default: Option<T>) -> impl FnMut(&'a str) -> IResult<&'a str, T> {
+    map_opt(end_of_metric, move |_| default.clone())
+}
 
-    map_res(digit1, into_u64)(input)
+fn into_f64(input: &str) -> Result<f64, std::num::ParseFloatError> {
+    input.parse::<f64>()
 }
 
-fn parse_timing(input: &str) -> IResult<&str, MetricKind> {
-    let (input, _) = tag("t|")(input)?;
+/// Value with a fractional part, like `1.5`. Integers are parsed on their own, so that they keep
+/// their range checks.
+fn decimal(input: &str) -> IResult<&str, f64> {
+    verify(
+        map_res(recognize(tuple((digit1, char('.'), digit1))), into_f64),
+        |value| value.is_finite(),
+    )(input)
+}
 
-    fn into_u64_timing(input: &str) -> Result<MetricKind, std::num::ParseIntError> {
-        Ok(MetricKind::Timing(
-            input.parse::<u64>()?,
-            TimerResolution::MilliSeconds,
-        ))
+/// Counter with one or more values separated by `:`, like `abc|c|1:2:3`, where sample rate
+/// applies to every value.
+fn parse_counter<'a>(input: &'a str, options: &Options) -> IResult<&'a str, Vec<MetricKind>> {
+    let (input, _) = char('c')(input)?;
+
+    fn into_u64(input: &str) -> Result<f64, std::num::ParseIntError> {
+        Ok(input.parse::<u64>()? as f64)
     }
 
-    fn into_u64(input: &str) -> Result<u64, std::num::ParseIntError> {
-        input.parse::<u64>()
+    fn scale(value: f64, rate: Option<f64>) -> Option<MetricKind> {
+        let Some(rate) = rate else {
+            return Some(MetricKind::Counter(value));
+        };
+
+        let scaled = value / rate;
+
+        // Whole values are scaled to whole counts
+        let scaled = if value.fract() == 0. {
+            scaled.round()
+        } else {
+            scaled
+        };
+
+        if scaled < u64::MAX as f64 {
+            Some(MetricKind::Counter(scaled))
+        } else {
+            None
+        }
     }
 
     alt((
+        preceded(
+            char('|'),
+            map_opt(
+                tuple((
+                    separated_list1(char(':'), alt((decimal, map_res(digit1, into_u64)))),
+                    opt(preceded(
+                        tag("|@"),
+                        cut(verify(
+                            map_res(
+                                recognize(tuple((digit1, opt(tuple((char('.'), digit1)))))),
+                                into_f64,
+                            ),
+                            |rate| *rate > 0. && *rate <= 1.,
+                        )),
+                    )),
+                )),
+                |(values, rate)| {
+                    values
+                        .into_iter()
+                        .map(|value| scale(value, rate))
+                        .collect::<Option<Vec<_>>>()
+                },
+            ),
+        ),
         map(
-            tuple((
-                map_res(digit1, into_u64),
+            omitted_value(Some(options.counter_default as f64)),
+            |value| vec![MetricKind::Counter(value)],
+        ),
+    ))(input)
+}
+
+/// Timing with one or more values separated by `:`, like `abc|t|1:2:3|ms`, where resolution and
+/// weight apply to every value.
+fn parse_timing<'a>(input: &'a str, options: &Options) -> IResult<&'a str, Vec<MetricKind>> {
+    let (input, _) = char('t')(input)?;
+
+    if let Ok((input, value)) = omitted_value(options.timing_default)(input) {
+        return Ok((
+            input,
+            vec![MetricKind::Timing(value, TimerResolution::MilliSeconds, 1)],
+        ));
+    }
+
+    let (input, _) = char('|')(input)?;
+
+    fn into_u64(input: &str) -> Result<u64, std::num::ParseIntError> {
+        input.parse::<u64>()
+    }
+
+    map(
+        tuple((
+            separated_list1(char(':'), map_res(digit1, into_u64)),
+            opt(preceded(
                 char('|'),
                 alt((
                     value(TimerResolution::NanoSeconds, tag("ns")),
@@ -44,42 +150,79 @@ fn parse_timing(input: &str) -> IResult<&str, MetricKind> {
                     value(TimerResolution::Seconds, tag("s")),
                 )),
             )),
-            |(value, _, resolution)| MetricKind::Timing(value, resolution),
+            opt(preceded(
+                tag("|w"),
+                cut(verify(map_res(digit1, into_u64), |weight| *weight > 0)),
+            )),
+        )),
+        |(values, resolution, weight)| {
+            let resolution = resolution.unwrap_or(TimerResolution::MilliSeconds);
+
+            values
+                .into_iter()
+                .map(|value| MetricKind::Timing(value, resolution.clone(), weight.unwrap_or(1)))
+                .collect()
+        },
+    )(input)
+}
+
+/// Integer or decimal number, which may be negative.
+fn signed(input: &str) -> IResult<&str, f64> {
+    fn into_i64(input: &str) -> Result<f64, std::num::ParseIntError> {
+        Ok(input.parse::<i64>()? as f64)
+    }
+
+    alt((
+        map(preceded(char('-'), decimal), |value| -value),
+        decimal,
+        map_res(
+            alt((recognize(tuple((tag("-"), digit1))), digit1)),
+            into_i64,
         ),
-        map_res(digit1, into_u64_timing),
     ))(input)
 }
 
-fn parse_gauge(input: &str) -> IResult<&str, MetricKind> {
-    let (input, _) = tag("g|")(input)?;
+fn parse_gauge<'a>(input: &'a str, options: &Options) -> IResult<&'a str, MetricKind> {
+    let (input, _) = char('g')(input)?;
 
-    fn into_i64_set(input: &str) -> Result<GaugeOperation, std::num::ParseIntError> {
-        Ok(GaugeOperation::Set(input.parse::<i64>()?))
+    if let Ok((input, value)) =
+        omitted_value(options.gauge_default.map(|value| value as f64))(input)
+    {
+        return Ok((input, MetricKind::Gauge(GaugeOperation::Set(value))));
     }
 
-    fn into_i64(input: &str) -> Result<i64, std::num::ParseIntError> {
-        input.parse::<i64>()
+    let (input, _) = char('|')(input)?;
+
+    fn into_i64(input: &str) -> Result<f64, std::num::ParseIntError> {
+        Ok(input.parse::<i64>()? as f64)
     }
 
     map(
         alt((
             map(char('x'), |_| GaugeOperation::Remove),
-            map_res(
-                alt((recognize(tuple((tag("-"), digit1))), digit1)),
-                into_i64_set,
+            map(
+                terminated(alt((decimal, map_res(digit1, into_i64))), tag("|s")),
+                GaugeOperation::Summary,
             ),
+            map(signed, GaugeOperation::Set),
             map(
                 tuple((
                     alt((char('+'), char('-'))),
                     char('='),
-                    map_res(digit1, into_i64),
+                    alt((decimal, map_res(digit1, into_i64))),
+                    opt(preceded(tag("|>="), signed)),
                 )),
-                |(kind, _, value)| {
-                    GaugeOperation::Modify(match kind {
+                |(kind, _, value, min)| {
+                    let value = match kind {
                         '+' => value,
                         '-' => -value,
                         _ => unreachable!("Should be covered by grammar"),
-                    })
+                    };
+
+                    match min {
+                        Some(min) => GaugeOperation::ModifyClamped(value, min),
+                        None => GaugeOperation::Modify(value),
+                    }
                 },
             ),
         )),
@@ -87,26 +230,285 @@ fn parse_gauge(input: &str) -> IResult<&str, MetricKind> {
     )(input)
 }
 
-fn parse_kind(input: &str) -> IResult<&str, MetricKind> {
-    alt((parse_counter, parse_timing, parse_gauge))(input)
+/// Absolute counter always carries a value, since there is nothing to default an ever-increasing
+/// total to.
+fn parse_absolute(input: &str) -> IResult<&str, MetricKind> {
+    fn into_u64(input: &str) -> Result<f64, std::num::ParseIntError> {
+        Ok(input.parse::<u64>()? as f64)
+    }
+
+    map(
+        preceded(tag("a|"), alt((decimal, map_res(digit1, into_u64)))),
+        MetricKind::Absolute,
+    )(input)
+}
+
+fn parse_histogram(input: &str) -> IResult<&str, MetricKind> {
+    fn into_u64(input: &str) -> Result<f64, std::num::ParseIntError> {
+        Ok(input.parse::<u64>()? as f64)
+    }
+
+    map(
+        preceded(tag("h|"), alt((decimal, map_res(digit1, into_u64)))),
+        MetricKind::Histogram,
+    )(input)
+}
+
+/// Set value is the rest of the line, so unlike names it can contain `|`.
+fn parse_set(input: &str) -> IResult<&str, MetricKind> {
+    map(preceded(tag("s|"), is_not("\n")), |value: &str| {
+        MetricKind::Set(value.to_string())
+    })(input)
 }
 
-fn parse_metric(input: &str) -> IResult<&str, Metric> {
-    let (input, name) = escaped_transform(
-        is_not("|\\"),
-        '\\',
-        alt((value("\\", tag("\\")), value("|", tag("|")))),
+fn parse_kind<'a>(input: &'a str, options: &Options) -> IResult<&'a str, Vec<MetricKind>> {
+    alt((
+        |input| parse_counter(input, options),
+        |input| parse_timing(input, options),
+        map(
+            alt((
+                |input| parse_gauge(input, options),
+                parse_absolute,
+                parse_histogram,
+                parse_set,
+            )),
+            |kind| vec![kind],
+        ),
+    ))(input)
+}
+
+/// Name with every value on the line, which is more than one for multi-value counters and
+/// timings.
+fn parse_metric<'a>(
+    input: &'a str,
+    options: &Options,
+) -> IResult<&'a str, (String, Vec<MetricKind>)> {
+    let (input, name) = map(
+        opt(escaped_transform(
+            is_not("|\\"),
+            '\\',
+            alt((value("\\", tag("\\")), value("|", tag("|")))),
+        )),
+        Option::unwrap_or_default,
     )(input)?;
 
     let (input, _) = char('|')(input)?;
 
-    let (input, kind) = parse_kind(input)?;
+    let (input, kinds) = parse_kind(input, options)?;
+
+    let name = if options.trim_names {
+        name.trim().to_string()
+    } else {
+        name
+    };
+
+    Ok((input, (name, kinds)))
+}
 
-    Ok((input, Metric { name, kind }))
+#[derive(Debug, Default, PartialEq)]
+pub struct Parsed {
+    pub metrics: Vec<Metric>,
+    /// Number of otherwise valid lines dropped because their name was empty.
+    pub empty_names: u64,
+    /// Number of otherwise valid lines dropped because their name contained control characters or
+    /// was too long.
+    pub invalid_names: u64,
+    /// Number of lines that could not be parsed.
+    pub malformed: u64,
 }
 
+impl Parsed {
+    /// Adds metrics of a line or a binary record, unless its name is empty or invalid, in which
+    /// case `record` is logged and counted instead.
+    fn push(
+        &mut self,
+        name: String,
+        kinds: Vec<MetricKind>,
+        options: &Options,
+        record: &dyn std::fmt::Debug,
+    ) {
+        if name.is_empty() {
+            log::warn!("Dropping metric with empty name: {:?}", record);
+
+            self.empty_names += 1;
+
+            return;
+        }
+
+        if let Some(reason) = invalid_name(&name, options) {
+            log::warn!("Dropping metric with invalid name, {reason}: {:?}", record);
+
+            self.invalid_names += 1;
+
+            return;
+        }
+
+        let name = prefixed(name, options);
+
+        self.metrics.extend(kinds.into_iter().map(|kind| Metric {
+            name: name.clone(),
+            kind,
+        }))
+    }
+}
+
+#[cfg(test)]
 pub fn parse_protocol(input: &str) -> Vec<Metric> {
-    separated_list1(char('\n'), parse_metric)(input).map_or_else(|_| vec![], |(_, metrics)| metrics)
+    parse_protocol_with(input, &Options::default()).metrics
+}
+
+/// Reason why a name can not be used, which is when it contains control characters, like NUL or
+/// tab, or when it's longer than allowed.
+fn invalid_name(name: &str, options: &Options) -> Option<String> {
+    if name.chars().any(char::is_control) {
+        return Some("it contains control characters".to_string());
+    }
+
+    match options.max_name_length {
+        Some(max_name_length) if name.len() > max_name_length => {
+            Some(format!("it's longer than {max_name_length} bytes"))
+        }
+        _ => None,
+    }
+}
+
+fn prefixed(name: String, options: &Options) -> String {
+    if options.prefix.is_empty() {
+        name
+    } else {
+        format!("{}.{name}", options.prefix)
+    }
+}
+
+/// Parses every line on its own, so a malformed line is logged and skipped without affecting
+/// other lines. Blank lines and comments, which are lines starting with `#`, are ignored, while
+/// lines with an empty metric name (after trimming, if enabled) or an invalid one are dropped and
+/// counted.
+pub fn parse_protocol_with(input: &str, options: &Options) -> Parsed {
+    input
+        .split('\n')
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .fold(Parsed::default(), |mut parsed, line| {
+            match terminated(|input| parse_metric(input, options), eof)(line) {
+                Ok((_, (name, kinds))) => parsed.push(name, kinds, options, &line),
+                Err(_) => {
+                    log::warn!("Skipping malformed line: {:?}", line);
+
+                    parsed.malformed += 1;
+                }
+            }
+
+            parsed
+        })
+}
+
+/// First byte of a binary datagram. It's never valid in UTF-8 so it can't start a text datagram.
+pub const BINARY_MAGIC: u8 = 0xFF;
+
+const BINARY_COUNTER: u8 = 0x01;
+const BINARY_TIMING: u8 = 0x02;
+const BINARY_GAUGE_SET: u8 = 0x03;
+const BINARY_GAUGE_MODIFY: u8 = 0x04;
+const BINARY_GAUGE_REMOVE: u8 = 0x05;
+const BINARY_GAUGE_SUMMARY: u8 = 0x06;
+
+fn parse_binary_resolution(input: &[u8]) -> IResult<&[u8], TimerResolution> {
+    map_opt(be_u8, |resolution| match resolution {
+        0 => Some(TimerResolution::Seconds),
+        1 => Some(TimerResolution::MilliSeconds),
+        2 => Some(TimerResolution::MicroSeconds),
+        3 => Some(TimerResolution::NanoSeconds),
+        _ => None,
+    })(input)
+}
+
+fn parse_binary_metric(input: &[u8]) -> IResult<&[u8], Metric> {
+    let (input, kind) = be_u8(input)?;
+
+    let (input, name_length) = be_u16(input)?;
+    let (input, name) = map_res(take(name_length), std::str::from_utf8)(input)?;
+
+    let (input, kind) = match kind {
+        BINARY_COUNTER => map(be_u64, |value| MetricKind::Counter(value as f64))(input)?,
+        BINARY_TIMING => map(
+            tuple((parse_binary_resolution, be_u64)),
+            |(resolution, value)| MetricKind::Timing(value, resolution, 1),
+        )(input)?,
+        BINARY_GAUGE_SET => map(be_i64, |value| {
+            MetricKind::Gauge(GaugeOperation::Set(value as f64))
+        })(input)?,
+        BINARY_GAUGE_MODIFY => map(be_i64, |value| {
+            MetricKind::Gauge(GaugeOperation::Modify(value as f64))
+        })(input)?,
+        BINARY_GAUGE_REMOVE => (input, MetricKind::Gauge(GaugeOperation::Remove)),
+        BINARY_GAUGE_SUMMARY => map(verify(be_i64, |value| *value >= 0), |value| {
+            MetricKind::Gauge(GaugeOperation::Summary(value as f64))
+        })(input)?,
+        _ => {
+            return Err(nom::Err::Error(nom::error::Error::new(
+                input,
+                nom::error::ErrorKind::Switch,
+            )))
+        }
+    };
+
+    Ok((
+        input,
+        Metric {
+            name: name.to_string(),
+            kind,
+        },
+    ))
+}
+
+/// Parses a binary datagram, which is a [`BINARY_MAGIC`] byte followed by records laid out as:
+///
+/// - kind as `u8` (`1` counter, `2` timing, `3` gauge set, `4` gauge modify, `5` gauge remove,
+///   `6` summary gauge set),
+/// - name length in bytes as `u16`,
+/// - UTF-8 name,
+/// - for timings, resolution as `u8` (`0` s, `1` ms, `2` us, `3` ns),
+/// - value as `u64` for counters and timings or `i64` for gauge set, modify and summary set,
+///   while gauge remove has no value.
+///
+/// All integers are big-endian. Same as with text protocol, records parsed before an invalid one
+/// are kept, and names are trimmed, validated and prefixed the same way. An invalid record, along
+/// with everything after it, is logged and counted as a single malformed one.
+pub fn parse_binary_with(input: &[u8], options: &Options) -> Parsed {
+    let (rest, metrics) =
+        preceded(tag([BINARY_MAGIC].as_slice()), many0(parse_binary_metric))(input)
+            .unwrap_or((input, vec![]));
+
+    let mut parsed = metrics
+        .into_iter()
+        .fold(Parsed::default(), |mut parsed, metric| {
+            let name = if options.trim_names {
+                metric.name.trim().to_string()
+            } else {
+                metric.name.clone()
+            };
+
+            parsed.push(name, vec![metric.kind], options, &metric.name);
+
+            parsed
+        });
+
+    if !rest.is_empty() {
+        log::warn!(
+            "Skipping malformed binary record at offset {}: {:?}",
+            input.len() - rest.len(),
+            rest
+        );
+
+        parsed.malformed += 1;
+    }
+
+    parsed
+}
+
+#[cfg(test)]
+pub fn parse_binary(input: &[u8]) -> Vec<Metric> {
+    parse_binary_with(input, &Options::default()).metrics
 }
 
 #[cfg(test)]
@@ -118,23 +520,295 @@ mod test {
         assert_eq!(
             vec![Metric {
                 name: "abc".to_string(),
-                kind: MetricKind::Counter(12),
+                kind: MetricKind::Counter(12.),
             }],
             parse_protocol("abc|c|12")
         );
     }
 
+    #[test]
+    fn counter_with_sample_rate_is_scaled() {
+        assert_eq!(
+            vec![
+                Metric {
+                    name: "abc".to_string(),
+                    kind: MetricKind::Counter(10.),
+                },
+                Metric {
+                    name: "abc".to_string(),
+                    kind: MetricKind::Counter(5.),
+                },
+                Metric {
+                    name: "abc".to_string(),
+                    kind: MetricKind::Counter(3.),
+                },
+            ],
+            parse_protocol("abc|c|1|@0.1\nabc|c|5\nabc|c|3|@1")
+        );
+
+        assert!(parse_protocol("abc|c|1|@0").is_empty());
+        assert!(parse_protocol("abc|c|1|@0.0").is_empty());
+        assert!(parse_protocol("abc|c|1|@1.5").is_empty());
+        assert!(parse_protocol("abc|c|18446744073709551615|@0.5").is_empty());
+    }
+
+    #[test]
+    fn counter_with_multiple_values_is_expanded() {
+        let counter = |value| Metric {
+            name: "abc".to_string(),
+            kind: MetricKind::Counter(value),
+        };
+
+        assert_eq!(
+            vec![counter(1.), counter(2.), counter(3.)],
+            parse_protocol("abc|c|1:2:3")
+        );
+        assert_eq!(
+            vec![counter(10.), counter(15.)],
+            parse_protocol("abc|c|1:1.5|@0.1")
+        );
+
+        assert!(parse_protocol("abc|c|1:").is_empty());
+        assert!(parse_protocol("abc|c|1::2").is_empty());
+        assert!(parse_protocol("abc|c|:1").is_empty());
+    }
+
+    #[test]
+    fn counter_without_value_defaults_to_one() {
+        assert_eq!(
+            vec![
+                Metric {
+                    name: "abc".to_string(),
+                    kind: MetricKind::Counter(1.),
+                },
+                Metric {
+                    name: "def".to_string(),
+                    kind: MetricKind::Counter(5.),
+                }
+            ],
+            parse_protocol("abc|c\ndef|c|5")
+        );
+    }
+
+    #[test]
+    fn omitted_values_use_configured_defaults() {
+        let options = Options {
+            counter_default: 3,
+            timing_default: Some(7),
+            gauge_default: Some(-2),
+            ..Options::default()
+        };
+
+        assert_eq!(
+            vec![
+                Metric {
+                    name: "abc".to_string(),
+                    kind: MetricKind::Counter(3.),
+                },
+                Metric {
+                    name: "abc".to_string(),
+                    kind: MetricKind::Timing(7, TimerResolution::MilliSeconds, 1),
+                },
+                Metric {
+                    name: "abc".to_string(),
+                    kind: MetricKind::Gauge(GaugeOperation::Set(-2.)),
+                }
+            ],
+            parse_protocol_with("abc|c\nabc|t\nabc|g", &options).metrics
+        );
+    }
+
+    #[test]
+    fn timer_and_gauge_without_value_are_not_parsed_by_default() {
+        assert!(parse_protocol("abc|t").is_empty());
+        assert!(parse_protocol("abc|g").is_empty());
+        assert!(parse_protocol("abc|c|").is_empty());
+    }
+
+    #[test]
+    fn malformed_lines_do_not_discard_valid_ones() {
+        assert_eq!(
+            vec![
+                Metric {
+                    name: "abc".to_string(),
+                    kind: MetricKind::Counter(1.),
+                },
+                Metric {
+                    name: "def".to_string(),
+                    kind: MetricKind::Gauge(GaugeOperation::Set(-3.)),
+                },
+                Metric {
+                    name: "ghi".to_string(),
+                    kind: MetricKind::Timing(4, TimerResolution::MilliSeconds, 1),
+                },
+            ],
+            parse_protocol("abc|c|1\ngarbage\ndef|g|-3\nabc|c|1x\n\nghi|t|4\nabc|t|5|w0\n")
+        );
+
+        assert_eq!(
+            3,
+            parse_protocol_with(
+                "abc|c|1\ngarbage\nabc|c|1x\nabc|t|5|w0",
+                &Options::default()
+            )
+            .malformed
+        );
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        assert_eq!(
+            Parsed {
+                metrics: vec![
+                    Metric {
+                        name: "abc".to_string(),
+                        kind: MetricKind::Counter(1.),
+                    },
+                    Metric {
+                        name: "def".to_string(),
+                        kind: MetricKind::Counter(2.),
+                    },
+                ],
+                ..Default::default()
+            },
+            parse_protocol_with(
+                "# replayed from capture\n\nabc|c|1\n  \n#def|c|3\n\t\r\ndef|c|2\n#",
+                &Options::default()
+            )
+        );
+    }
+
     #[test]
     fn counter_with_escaped_chars_can_be_parsed() {
         assert_eq!(
             vec![Metric {
                 name: "a\\b|c".to_string(),
-                kind: MetricKind::Counter(12),
+                kind: MetricKind::Counter(12.),
             }],
             parse_protocol("a\\\\b\\|c|c|12")
         );
     }
 
+    #[test]
+    fn names_are_trimmed_only_when_enabled() {
+        assert_eq!(
+            vec![Metric {
+                name: " abc ".to_string(),
+                kind: MetricKind::Counter(1.),
+            }],
+            parse_protocol(" abc |c|1")
+        );
+
+        let options = Options {
+            trim_names: true,
+            ..Options::default()
+        };
+
+        assert_eq!(
+            vec![
+                Metric {
+                    name: "abc".to_string(),
+                    kind: MetricKind::Counter(1.),
+                },
+                Metric {
+                    name: "abc".to_string(),
+                    kind: MetricKind::Counter(2.),
+                }
+            ],
+            parse_protocol_with(" abc |c|1\nabc|c|2", &options).metrics
+        );
+    }
+
+    #[test]
+    fn metrics_with_empty_names_are_dropped_and_counted() {
+        assert_eq!(
+            Parsed {
+                metrics: vec![Metric {
+                    name: "abc".to_string(),
+                    kind: MetricKind::Counter(2.),
+                }],
+                empty_names: 1,
+                invalid_names: 0,
+                malformed: 0,
+            },
+            parse_protocol_with("|c|1\nabc|c|2", &Options::default())
+        );
+
+        let options = Options {
+            trim_names: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Parsed {
+                metrics: vec![],
+                empty_names: 2,
+                invalid_names: 0,
+                malformed: 0,
+            },
+            parse_protocol_with("  |c|1\n|g|2", &options)
+        );
+    }
+
+    #[test]
+    fn metrics_with_invalid_names_are_dropped_and_counted() {
+        let options = Options {
+            max_name_length: Some(5),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Parsed {
+                metrics: vec![Metric {
+                    name: "abcde".to_string(),
+                    kind: MetricKind::Counter(4.),
+                }],
+                empty_names: 1,
+                invalid_names: 3,
+                malformed: 0,
+            },
+            parse_protocol_with("a\0b|c|1\na\tb|c|2\nabcdef|c|3\nabcde|c|4\n|c|5", &options)
+        );
+    }
+
+    #[test]
+    fn prefix_is_prepended_to_every_name() {
+        let options = Options {
+            prefix: "svc".to_string(),
+            max_name_length: Some(8),
+            ..Default::default()
+        };
+
+        let mut registry = crate::metrics::Registry::default();
+
+        parse_protocol_with("requests|c|2\nre\\|q|g|1\nrequests|c|3", &options)
+            .metrics
+            .iter()
+            .chain(
+                &parse_binary_with(
+                    &encode_binary(&[Metric {
+                        name: "latency".to_string(),
+                        kind: MetricKind::Counter(1.),
+                    }]),
+                    &options,
+                )
+                .metrics,
+            )
+            .for_each(|metric| {
+                registry.add(metric);
+            });
+
+        let time_frame = registry.finalize();
+
+        let mut names = time_frame.counters.keys().collect::<Vec<_>>();
+
+        names.sort();
+
+        assert_eq!(vec!["svc.latency", "svc.requests"], names);
+        assert_eq!(5., time_frame.counters["svc.requests"].sum());
+        assert_eq!(1., time_frame.gauges["svc.re|q"]);
+    }
+
     #[test]
     fn counter_with_very_big_number_is_not_parsed_but_does_not_crash_program() {
         assert!(parse_protocol(
@@ -148,7 +822,7 @@ mod test {
         assert_eq!(
             vec![Metric {
                 name: "abc".to_string(),
-                kind: MetricKind::Gauge(GaugeOperation::Set(12)),
+                kind: MetricKind::Gauge(GaugeOperation::Set(12.)),
             }],
             parse_protocol("abc|g|12")
         );
@@ -156,7 +830,7 @@ mod test {
         assert_eq!(
             vec![Metric {
                 name: "abc".to_string(),
-                kind: MetricKind::Gauge(GaugeOperation::Set(-12)),
+                kind: MetricKind::Gauge(GaugeOperation::Set(-12.)),
             }],
             parse_protocol("abc|g|-12")
         );
@@ -164,7 +838,7 @@ mod test {
         assert_eq!(
             vec![Metric {
                 name: "abc".to_string(),
-                kind: MetricKind::Gauge(GaugeOperation::Modify(12)),
+                kind: MetricKind::Gauge(GaugeOperation::Modify(12.)),
             }],
             parse_protocol("abc|g|+=12")
         );
@@ -172,11 +846,29 @@ mod test {
         assert_eq!(
             vec![Metric {
                 name: "abc".to_string(),
-                kind: MetricKind::Gauge(GaugeOperation::Modify(-12)),
+                kind: MetricKind::Gauge(GaugeOperation::Modify(-12.)),
             }],
             parse_protocol("abc|g|-=12")
         );
 
+        assert_eq!(
+            vec![Metric {
+                name: "abc".to_string(),
+                kind: MetricKind::Gauge(GaugeOperation::ModifyClamped(-12., 0.)),
+            }],
+            parse_protocol("abc|g|-=12|>=0")
+        );
+
+        assert_eq!(
+            vec![Metric {
+                name: "abc".to_string(),
+                kind: MetricKind::Gauge(GaugeOperation::ModifyClamped(1.5, -2.5)),
+            }],
+            parse_protocol("abc|g|+=1.5|>=-2.5")
+        );
+
+        assert_eq!(Vec::<Metric>::new(), parse_protocol("abc|g|-=12|>="));
+
         assert_eq!(
             vec![Metric {
                 name: "abc".to_string(),
@@ -184,6 +876,130 @@ mod test {
             }],
             parse_protocol("abc|g|x")
         );
+
+        assert_eq!(
+            vec![Metric {
+                name: "abc".to_string(),
+                kind: MetricKind::Gauge(GaugeOperation::Summary(12.)),
+            }],
+            parse_protocol("abc|g|12|s")
+        );
+    }
+
+    #[test]
+    fn set_can_be_parsed() {
+        assert_eq!(
+            vec![Metric {
+                name: "user".to_string(),
+                kind: MetricKind::Set("alice".to_string()),
+            }],
+            parse_protocol("user|s|alice")
+        );
+
+        assert_eq!(
+            vec![Metric {
+                name: "user".to_string(),
+                kind: MetricKind::Set("a|b c".to_string()),
+            }],
+            parse_protocol("user|s|a|b c")
+        );
+
+        assert!(parse_protocol("user|s|").is_empty());
+        assert!(parse_protocol("user|s").is_empty());
+    }
+
+    #[test]
+    fn absolute_counter_can_be_parsed() {
+        assert_eq!(
+            vec![
+                Metric {
+                    name: "eth0.rx_bytes".to_string(),
+                    kind: MetricKind::Absolute(1024.),
+                },
+                Metric {
+                    name: "load".to_string(),
+                    kind: MetricKind::Absolute(2.5),
+                },
+            ],
+            parse_protocol("eth0.rx_bytes|a|1024\nload|a|2.5")
+        );
+
+        assert_eq!(
+            Parsed {
+                malformed: 2,
+                ..Default::default()
+            },
+            parse_protocol_with("eth0.rx_bytes|a|\neth0.rx_bytes|a|-3", &Options::default())
+        );
+    }
+
+    #[test]
+    fn histogram_can_be_parsed() {
+        assert_eq!(
+            vec![
+                Metric {
+                    name: "latency".to_string(),
+                    kind: MetricKind::Histogram(0.25),
+                },
+                Metric {
+                    name: "size".to_string(),
+                    kind: MetricKind::Histogram(512.),
+                },
+            ],
+            parse_protocol("latency|h|0.25\nsize|h|512\nlatency|h|")
+        );
+    }
+
+    #[test]
+    fn same_set_value_is_counted_once() {
+        let mut registry = crate::metrics::Registry::default();
+
+        for metric in parse_protocol("user|s|alice\nuser|s|bob\nuser|s|alice") {
+            assert_eq!(crate::metrics::Added::Aggregated, registry.add(&metric));
+        }
+
+        assert_eq!(2, registry.finalize().sets["user"]);
+    }
+
+    #[test]
+    fn decimal_values_can_be_parsed() {
+        assert_eq!(
+            vec![
+                Metric {
+                    name: "abc".to_string(),
+                    kind: MetricKind::Counter(0.25),
+                },
+                Metric {
+                    name: "abc".to_string(),
+                    kind: MetricKind::Counter(5.),
+                },
+                Metric {
+                    name: "abc".to_string(),
+                    kind: MetricKind::Counter(3.75),
+                },
+                Metric {
+                    name: "abc".to_string(),
+                    kind: MetricKind::Gauge(GaugeOperation::Set(-1.5)),
+                },
+                Metric {
+                    name: "abc".to_string(),
+                    kind: MetricKind::Gauge(GaugeOperation::Modify(-0.5)),
+                },
+                Metric {
+                    name: "abc".to_string(),
+                    kind: MetricKind::Gauge(GaugeOperation::Summary(36.6)),
+                },
+            ],
+            parse_protocol(
+                "abc|c|0.25\nabc|c|2.5|@0.5\nabc|c|1.5|@0.4\nabc|g|-1.5\nabc|g|-=0.5\nabc|g|36.6|s"
+            )
+        );
+
+        assert!(parse_protocol("abc|c|1.").is_empty());
+        assert!(parse_protocol("abc|c|.5").is_empty());
+        assert!(parse_protocol("abc|c|-1.5").is_empty());
+        assert!(parse_protocol("abc|g|-1.5|s").is_empty());
+        assert!(parse_protocol(&format!("abc|g|{}.0", "9".repeat(400))).is_empty());
     }
 
     #[test]
@@ -204,7 +1020,7 @@ mod test {
         assert_eq!(
             vec![Metric {
                 name: "abc".to_string(),
-                kind: MetricKind::Timing(123, TimerResolution::MilliSeconds),
+                kind: MetricKind::Timing(123, TimerResolution::MilliSeconds, 1),
             }],
             parse_protocol("abc|t|123")
         );
@@ -212,7 +1028,7 @@ mod test {
         assert_eq!(
             vec![Metric {
                 name: "abc".to_string(),
-                kind: MetricKind::Timing(123, TimerResolution::MilliSeconds),
+                kind: MetricKind::Timing(123, TimerResolution::MilliSeconds, 1),
             }],
             parse_protocol("abc|t|123|ms")
         );
@@ -220,7 +1036,7 @@ mod test {
         assert_eq!(
             vec![Metric {
                 name: "abc".to_string(),
-                kind: MetricKind::Timing(123, TimerResolution::Seconds),
+                kind: MetricKind::Timing(123, TimerResolution::Seconds, 1),
             }],
             parse_protocol("abc|t|123|s")
         );
@@ -228,7 +1044,7 @@ mod test {
         assert_eq!(
             vec![Metric {
                 name: "abc".to_string(),
-                kind: MetricKind::Timing(123, TimerResolution::MicroSeconds),
+                kind: MetricKind::Timing(123, TimerResolution::MicroSeconds, 1),
             }],
             parse_protocol("abc|t|123|us")
         );
@@ -236,12 +1052,54 @@ mod test {
         assert_eq!(
             vec![Metric {
                 name: "abc".to_string(),
-                kind: MetricKind::Timing(123, TimerResolution::NanoSeconds),
+                kind: MetricKind::Timing(123, TimerResolution::NanoSeconds, 1),
             }],
             parse_protocol("abc|t|123|ns")
         );
     }
 
+    #[test]
+    fn timer_with_weight_can_be_parsed() {
+        assert_eq!(
+            vec![
+                Metric {
+                    name: "abc".to_string(),
+                    kind: MetricKind::Timing(123, TimerResolution::MilliSeconds, 5),
+                },
+                Metric {
+                    name: "abc".to_string(),
+                    kind: MetricKind::Timing(123, TimerResolution::Seconds, 2),
+                }
+            ],
+            parse_protocol("abc|t|123|w5\nabc|t|123|s|w2")
+        );
+
+        assert!(parse_protocol("abc|t|123|ms|w0").is_empty());
+        assert!(parse_protocol("abc|t|123|ms|w18446744073709551616").is_empty());
+    }
+
+    #[test]
+    fn timer_with_multiple_values_is_expanded() {
+        let timing = |value, resolution, weight| Metric {
+            name: "abc".to_string(),
+            kind: MetricKind::Timing(value, resolution, weight),
+        };
+
+        assert_eq!(
+            vec![
+                timing(1, TimerResolution::MilliSeconds, 1),
+                timing(2, TimerResolution::MilliSeconds, 1),
+                timing(3, TimerResolution::MilliSeconds, 1),
+                timing(4, TimerResolution::Seconds, 2),
+                timing(5, TimerResolution::Seconds, 2),
+            ],
+            parse_protocol("abc|t|1:2:3\nabc|t|4:5|s|w2")
+        );
+
+        assert!(parse_protocol("abc|t|1:1.5").is_empty());
+        assert!(parse_protocol("abc|t|1:|ms").is_empty());
+    }
+
     #[test]
     fn timer_with_very_big_number_is_not_parsed_but_does_not_crash_program() {
         assert!(parse_protocol(
@@ -256,4 +1114,151 @@ mod test {
 
         assert!(parse_protocol("abc|t|18446744073709551616|ns").is_empty());
     }
+
+    fn encode_binary(metrics: &[Metric]) -> Vec<u8> {
+        let mut output = vec![BINARY_MAGIC];
+
+        for metric in metrics {
+            let (kind, value) = match &metric.kind {
+                MetricKind::Counter(value) => {
+                    (BINARY_COUNTER, (*value as u64).to_be_bytes().to_vec())
+                }
+                MetricKind::Timing(value, resolution, _) => (BINARY_TIMING, {
+                    let mut bytes = vec![match resolution {
+                        TimerResolution::Seconds => 0,
+                        TimerResolution::MilliSeconds => 1,
+                        TimerResolution::MicroSeconds => 2,
+                        TimerResolution::NanoSeconds => 3,
+                    }];
+                    bytes.extend(value.to_be_bytes());
+                    bytes
+                }),
+                MetricKind::Gauge(GaugeOperation::Set(value)) => {
+                    (BINARY_GAUGE_SET, (*value as i64).to_be_bytes().to_vec())
+                }
+                MetricKind::Gauge(GaugeOperation::Modify(value)) => {
+                    (BINARY_GAUGE_MODIFY, (*value as i64).to_be_bytes().to_vec())
+                }
+                MetricKind::Gauge(GaugeOperation::Remove) => (BINARY_GAUGE_REMOVE, vec![]),
+                MetricKind::Gauge(GaugeOperation::ModifyClamped(..)) => {
+                    unreachable!("Clamped gauge modify is only supported in text protocol")
+                }
+                MetricKind::Gauge(GaugeOperation::Summary(value)) => {
+                    (BINARY_GAUGE_SUMMARY, (*value as i64).to_be_bytes().to_vec())
+                }
+                MetricKind::Absolute(_) | MetricKind::Histogram(_) | MetricKind::Set(_) => {
+                    unreachable!(
+                        "Absolute counters, histograms and sets are only supported in text protocol"
+                    )
+                }
+            };
+
+            output.push(kind);
+            output.extend((metric.name.len() as u16).to_be_bytes());
+            output.extend(metric.name.as_bytes());
+            output.extend(value);
+        }
+
+        output
+    }
+
+    #[test]
+    fn binary_metrics_can_be_parsed() {
+        let metrics = vec![
+            Metric {
+                name: "counter".to_string(),
+                kind: MetricKind::Counter(u64::MAX as f64),
+            },
+            Metric {
+                name: "tim|ing".to_string(),
+                kind: MetricKind::Timing(123, TimerResolution::MicroSeconds, 1),
+            },
+            Metric {
+                name: "gauge".to_string(),
+                kind: MetricKind::Gauge(GaugeOperation::Set(-12.)),
+            },
+            Metric {
+                name: "gauge".to_string(),
+                kind: MetricKind::Gauge(GaugeOperation::Modify(i64::MIN as f64)),
+            },
+            Metric {
+                name: "gauge".to_string(),
+                kind: MetricKind::Gauge(GaugeOperation::Remove),
+            },
+            Metric {
+                name: "gauge".to_string(),
+                kind: MetricKind::Gauge(GaugeOperation::Summary(i64::MAX as f64)),
+            },
+        ];
+
+        assert_eq!(metrics, parse_binary(&encode_binary(&metrics)));
+    }
+
+    #[test]
+    fn binary_metrics_before_invalid_record_are_kept() {
+        let metrics = vec![Metric {
+            name: "abc".to_string(),
+            kind: MetricKind::Counter(12.),
+        }];
+
+        let mut payload = encode_binary(&metrics);
+        payload.extend([BINARY_COUNTER, 0, 10, b'a']);
+
+        assert_eq!(metrics, parse_binary(&payload));
+
+        assert!(parse_binary(&[BINARY_MAGIC, 0x09, 0, 0]).is_empty());
+        assert!(parse_binary(b"abc|c|12").is_empty());
+    }
+
+    #[test]
+    fn truncated_binary_record_is_counted_as_malformed() {
+        let metrics = vec![Metric {
+            name: "abc".to_string(),
+            kind: MetricKind::Counter(12.),
+        }];
+
+        let mut payload = encode_binary(&metrics);
+        payload.extend(&encode_binary(&metrics)[1..6]);
+
+        assert_eq!(
+            Parsed {
+                metrics,
+                malformed: 1,
+                ..Default::default()
+            },
+            parse_binary_with(&payload, &Options::default())
+        );
+    }
+
+    #[test]
+    fn binary_names_are_validated_like_text_ones() {
+        let options = Options {
+            trim_names: true,
+            max_name_length: Some(5),
+            ..Default::default()
+        };
+        let counter = |name: &str| Metric {
+            name: name.to_string(),
+            kind: MetricKind::Counter(1.),
+        };
+
+        assert_eq!(
+            Parsed {
+                metrics: vec![counter("abc")],
+                empty_names: 2,
+                invalid_names: 2,
+                malformed: 0,
+            },
+            parse_binary_with(
+                &encode_binary(&[
+                    counter(""),
+                    counter("  "),
+                    counter("a\0b"),
+                    counter("abcdef"),
+                    counter(" abc "),
+                ]),
+                &options
+            )
+        );
+    }
 }
@@ -0,0 +1,68 @@
+use std::borrow::Cow;
+
+use regex::Regex;
+
+use crate::metrics::Metric;
+
+/// Rewrites metric names using every rule in order, where each rule replaces all matches of its
+/// pattern. Replacement can reference capture groups, like `$1` or `${name}`.
+#[derive(Debug, Default)]
+pub struct Rewriter {
+    rules: Vec<(Regex, String)>,
+}
+
+impl Rewriter {
+    pub fn new(rules: Vec<(Regex, String)>) -> Self {
+        Self { rules }
+    }
+
+    pub fn rewrite(&self, metric: &mut Metric) {
+        for (pattern, replacement) in &self.rules {
+            if let Cow::Owned(name) = pattern.replace_all(&metric.name, replacement.as_str()) {
+                metric.name = name;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::MetricKind;
+
+    fn rewrite(rewriter: &Rewriter, name: &str) -> String {
+        let mut metric = Metric {
+            name: name.to_string(),
+            kind: MetricKind::Counter(1.),
+        };
+
+        rewriter.rewrite(&mut metric);
+
+        metric.name
+    }
+
+    #[test]
+    fn numeric_segments_are_collapsed() {
+        let rewriter = Rewriter::new(vec![(Regex::new(r"/\d+\b").unwrap(), "/:id".to_string())]);
+
+        assert_eq!("/user/:id/profile", rewrite(&rewriter, "/user/123/profile"));
+        assert_eq!("/user/:id/profile", rewrite(&rewriter, "/user/456/profile"));
+        assert_eq!("/user/:id/:id", rewrite(&rewriter, "/user/1/2"));
+        assert_eq!("/user/12ab", rewrite(&rewriter, "/user/12ab"));
+    }
+
+    #[test]
+    fn captures_are_templated_in_order() {
+        let rewriter = Rewriter::new(vec![
+            (
+                Regex::new(r"^/(?P<resource>\w+)/\d+/(\w+)$").unwrap(),
+                "${resource}.$2".to_string(),
+            ),
+            (Regex::new(r"^user\.").unwrap(), "users.".to_string()),
+        ]);
+
+        assert_eq!("users.profile", rewrite(&rewriter, "/user/123/profile"));
+        assert_eq!("order.items", rewrite(&rewriter, "/order/7/items"));
+        assert_eq!("/user/profile", rewrite(&rewriter, "/user/profile"));
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Keeps gauges in a JSON file, so that a gauge set once survives a restart. Snapshot is written
+/// to a temporary file first and then renamed, so a crash mid-write never leaves a partial one.
+#[derive(Debug)]
+pub struct GaugeSnapshot {
+    path: PathBuf,
+}
+
+impl GaugeSnapshot {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Gauges from the snapshot, or none when it's missing or can not be read.
+    pub fn load(&self) -> HashMap<String, f64> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return HashMap::new(),
+            Err(err) => {
+                log::warn!(
+                    "Unable to read gauge snapshot {:?}, starting without gauges: {err}",
+                    self.path
+                );
+
+                return HashMap::new();
+            }
+        };
+
+        match serde_json::from_str::<HashMap<String, f64>>(&content) {
+            Ok(gauges) => gauges,
+            Err(err) => {
+                log::warn!(
+                    "Gauge snapshot {:?} is corrupt, starting without gauges: {err}",
+                    self.path
+                );
+
+                HashMap::new()
+            }
+        }
+    }
+
+    pub fn save(&self, gauges: &HashMap<String, f64>) -> io::Result<()> {
+        let temporary = temporary_path(&self.path);
+
+        fs::write(&temporary, serde_json::to_string(gauges)?)?;
+        fs::rename(&temporary, &self.path)
+    }
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+
+    name.push(".tmp");
+
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("metco-snapshot-{}-{name}.json", std::process::id()));
+
+        let _ = fs::remove_file(&path);
+
+        path
+    }
+
+    #[test]
+    fn gauges_survive_save_and_load() {
+        let snapshot = GaugeSnapshot::new(temp_path("roundtrip"));
+        let gauges = HashMap::from([("abc".to_string(), -1.5), ("a\"b\nc".to_string(), 3.)]);
+
+        assert!(snapshot.load().is_empty());
+
+        snapshot.save(&gauges).unwrap();
+
+        assert_eq!(gauges, snapshot.load());
+    }
+
+    #[test]
+    fn corrupt_snapshot_is_loaded_empty() {
+        let path = temp_path("corrupt");
+
+        fs::write(&path, "{\"abc\": 1").unwrap();
+
+        assert!(GaugeSnapshot::new(path).load().is_empty());
+    }
+}
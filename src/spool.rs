@@ -0,0 +1,161 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+const MARKER_SUFFIX: &str = ".done";
+
+/// Reads metrics from files in a spool directory. File is ingested only once its marker exists,
+/// which is an empty file with the same name and `.done` suffix, so partially written files are
+/// never read. Files are ingested in name order, and once read they are moved to the archive
+/// directory if one is set, or deleted otherwise.
+///
+/// File that can not be read is left in place with its marker, so it's read again on the next
+/// poll. Marker of a read file is removed before the file is ingested, so a file that can not be
+/// archived or deleted is never ingested twice.
+#[derive(Debug)]
+pub struct Spool {
+    dir: PathBuf,
+    archive: Option<PathBuf>,
+}
+
+impl Spool {
+    pub fn new(dir: PathBuf, archive: Option<PathBuf>) -> Self {
+        Self { dir, archive }
+    }
+
+    pub fn collect(&self) -> io::Result<Vec<String>> {
+        let mut names = fs::read_dir(&self.dir)?
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(MARKER_SUFFIX))
+                    .map(String::from)
+            })
+            .collect::<Vec<_>>();
+
+        names.sort();
+
+        let mut payloads = vec![];
+
+        for name in names {
+            let path = self.dir.join(&name);
+            let marker = self.dir.join(format!("{name}{MARKER_SUFFIX}"));
+
+            let payload = match fs::read_to_string(&path) {
+                Ok(payload) => Some(payload),
+                // Marker left without its file is only removed
+                Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => {
+                    log::warn!("Unable to read spool file {path:?}, retrying on next poll: {err}");
+
+                    continue;
+                }
+            };
+
+            if let Err(err) = fs::remove_file(&marker) {
+                log::error!("Unable to remove marker {marker:?}, skipping spool file: {err}");
+
+                continue;
+            }
+
+            payloads.extend(payload);
+
+            let cleanup = match &self.archive {
+                Some(archive) if path.exists() => fs::rename(&path, archive.join(&name)),
+                _ if path.exists() => fs::remove_file(&path),
+                _ => Ok(()),
+            };
+
+            if let Err(err) = cleanup {
+                log::error!("Unable to clean up spool file {path:?}, it's left in place: {err}");
+            }
+        }
+
+        Ok(payloads)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("metco-spool-{}-{name}", std::process::id()));
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn only_files_with_marker_are_ingested_and_deleted() {
+        let dir = temp_dir("delete");
+
+        fs::write(dir.join("b.metrics"), "b|c|2").unwrap();
+        fs::write(dir.join("b.metrics.done"), "").unwrap();
+        fs::write(dir.join("a.metrics"), "a|c|1").unwrap();
+        fs::write(dir.join("a.metrics.done"), "").unwrap();
+        fs::write(dir.join("c.metrics"), "c|c").unwrap();
+
+        let spool = Spool::new(dir.clone(), None);
+
+        assert_eq!(vec!["a|c|1", "b|c|2"], spool.collect().unwrap());
+
+        let mut remaining = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        remaining.sort();
+
+        assert_eq!(vec!["c.metrics"], remaining);
+        assert!(spool.collect().unwrap().is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ingested_files_are_archived() {
+        let dir = temp_dir("archive");
+        let archive = temp_dir("archive-target");
+
+        fs::write(dir.join("a.metrics"), "a|c|1").unwrap();
+        fs::write(dir.join("a.metrics.done"), "").unwrap();
+
+        let spool = Spool::new(dir.clone(), Some(archive.clone()));
+
+        assert_eq!(vec!["a|c|1"], spool.collect().unwrap());
+        assert_eq!(0, fs::read_dir(&dir).unwrap().count());
+        assert_eq!(
+            "a|c|1",
+            fs::read_to_string(archive.join("a.metrics")).unwrap()
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(archive).unwrap();
+    }
+
+    #[test]
+    fn unreadable_files_are_kept_and_failed_cleanup_is_not_ingested_again() {
+        let dir = temp_dir("failures");
+
+        fs::create_dir(dir.join("a.metrics")).unwrap();
+        fs::write(dir.join("a.metrics.done"), "").unwrap();
+        fs::write(dir.join("b.metrics"), "b|c|2").unwrap();
+        fs::write(dir.join("b.metrics.done"), "").unwrap();
+
+        let spool = Spool::new(dir.clone(), Some(dir.join("missing")));
+
+        assert_eq!(vec!["b|c|2"], spool.collect().unwrap());
+        assert!(dir.join("a.metrics.done").exists());
+        assert!(dir.join("b.metrics").exists());
+        assert!(!dir.join("b.metrics.done").exists());
+
+        assert!(spool.collect().unwrap().is_empty());
+        assert!(dir.join("a.metrics.done").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}